@@ -0,0 +1,5 @@
+// the shortener embeds its migrations with `sqlx::migrate!`, which does not tell cargo
+// to rebuild when one is added or changed
+fn main() {
+    println!("cargo:rerun-if-changed=examples/shortener/migrations");
+}
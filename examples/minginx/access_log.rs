@@ -0,0 +1,130 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use http::{Method, StatusCode};
+use tokio::time::Instant;
+use tracing::{info, Level, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::{filter::Targets, fmt, registry::LookupSpan, Layer};
+
+use crate::{config::AccessLogConfig, proxy::CloseReason, state::ActiveConnection};
+
+pub const TARGET: &str = "minginx::access";
+
+/// one record per proxied connection, emitted when the connection is closed, also for
+/// connections that were refused or failed before they reached an upstream
+#[derive(Debug)]
+pub struct AccessLog {
+    listener: String,
+    upstream: Option<String>,
+    start: Instant,
+}
+
+/// one record per request of an http connection, emitted once the response body is sent
+/// or dropped, the byte counts are those of the request and response bodies
+#[derive(Debug)]
+pub struct RequestLog {
+    client: SocketAddr,
+    listener: String,
+    method: Method,
+    path: String,
+    upstream: Option<String>,
+    status: StatusCode,
+    pub request_bytes: Arc<AtomicU64>,
+    pub response_bytes: Arc<AtomicU64>,
+    start: Instant,
+}
+
+/// write the access records as JSON lines to a daily rolling file
+pub fn layer<S>(config: &AccessLogConfig) -> (impl Layer<S>, WorkerGuard)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let file_appender =
+        tracing_appender::rolling::daily(&config.directory, &config.file_name_prefix);
+    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+    let layer = fmt::Layer::new()
+        .json()
+        .flatten_event(true)
+        .with_current_span(false)
+        .with_span_list(false)
+        .with_writer(non_blocking)
+        .with_filter(Targets::new().with_target(TARGET, Level::INFO));
+    (layer, guard)
+}
+
+impl AccessLog {
+    pub fn new(listener: &str) -> Self {
+        Self {
+            listener: listener.to_string(),
+            upstream: None,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn set_upstream(&mut self, upstream: &str) {
+        self.upstream = Some(upstream.to_string());
+    }
+
+    /// the client is the one named by a PROXY protocol header, if there was one
+    pub fn finish(self, connection: &ActiveConnection, close_reason: &CloseReason) {
+        let traffic = &connection.traffic;
+        info!(
+            target: TARGET,
+            client = %connection.client(),
+            listener = self.listener,
+            upstream = self.upstream,
+            client_to_upstream_bytes = traffic.client_to_upstream.load(Ordering::Relaxed),
+            upstream_to_client_bytes = traffic.upstream_to_client.load(Ordering::Relaxed),
+            duration_ms = self.start.elapsed().as_millis() as u64,
+            close_reason = %close_reason,
+            error = close_reason.error(),
+            "connection closed"
+        );
+    }
+}
+
+impl RequestLog {
+    pub fn new(client: SocketAddr, listener: &str, method: &Method, path: &str) -> Self {
+        Self {
+            client,
+            listener: listener.to_string(),
+            method: method.clone(),
+            path: path.to_string(),
+            upstream: None,
+            status: StatusCode::OK,
+            request_bytes: Arc::default(),
+            response_bytes: Arc::default(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn set_upstream(&mut self, upstream: &str) {
+        self.upstream = Some(upstream.to_string());
+    }
+
+    pub fn set_status(&mut self, status: StatusCode) {
+        self.status = status;
+    }
+
+    pub fn finish(self) {
+        info!(
+            target: TARGET,
+            client = %self.client,
+            listener = self.listener,
+            method = %self.method,
+            path = self.path,
+            status = self.status.as_u16(),
+            upstream = self.upstream,
+            client_to_upstream_bytes = self.request_bytes.load(Ordering::Relaxed),
+            upstream_to_client_bytes = self.response_bytes.load(Ordering::Relaxed),
+            duration_ms = self.start.elapsed().as_millis() as u64,
+            "request completed"
+        );
+    }
+}
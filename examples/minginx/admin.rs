@@ -0,0 +1,147 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::Result;
+use axum::{
+    extract::{Path, State as AxumState},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpListener, sync::watch};
+use tracing::{info, warn};
+
+use crate::{
+    config::Config,
+    health::{ServerHealth, ServerStatus},
+    reload::Reloader,
+    state::{ActiveConnection, State},
+};
+
+#[derive(Debug, Clone)]
+struct AdminState {
+    config: watch::Receiver<Arc<Config>>,
+    state: Arc<State>,
+    reloader: Arc<Reloader>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    Enable,
+    Drain,
+    Disable,
+}
+
+#[derive(Debug, Serialize)]
+struct ServerView {
+    address: String,
+    #[serde(flatten)]
+    health: ServerHealth,
+    active_connections: usize,
+}
+
+/// inspect and control the running proxy over http
+pub async fn serve(
+    listener: TcpListener,
+    config: watch::Receiver<Arc<Config>>,
+    state: Arc<State>,
+    reloader: Arc<Reloader>,
+) -> Result<()> {
+    let app = Router::new()
+        .route("/config", get(get_config))
+        .route("/reload", post(reload))
+        .route("/connections", get(list_connections))
+        .route("/upstreams", get(list_upstreams))
+        .route("/servers/:server/:action", post(update_server))
+        .with_state(AdminState {
+            config,
+            state,
+            reloader,
+        });
+    axum::serve(listener, app.into_make_service()).await?;
+    Ok(())
+}
+
+/// the config in use, with defaults filled in
+async fn get_config(AxumState(admin): AxumState<AdminState>) -> Response {
+    let config = admin.config.borrow().clone();
+    Json(&*config).into_response()
+}
+
+async fn reload(AxumState(admin): AxumState<AdminState>) -> Response {
+    info!("Reload requested through the admin api");
+    match admin.reloader.reload().await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            warn!("Invalid config, keep the current one: {:?}", e);
+            (StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", e)).into_response()
+        }
+    }
+}
+
+async fn list_connections(AxumState(admin): AxumState<AdminState>) -> Response {
+    let connections = admin.state.active.list();
+    let connections: Vec<&ActiveConnection> = connections.iter().map(Arc::as_ref).collect();
+    Json(connections).into_response()
+}
+
+/// the servers of each upstream with their status and health
+async fn list_upstreams(
+    AxumState(admin): AxumState<AdminState>,
+) -> Json<BTreeMap<String, Vec<ServerView>>> {
+    let config = admin.config.borrow().clone();
+    let upstreams = config
+        .upstreams
+        .iter()
+        .map(|(name, upstream)| {
+            let servers = upstream
+                .servers
+                .iter()
+                .map(|server| admin.server_view(server))
+                .collect();
+            (name.clone(), servers)
+        })
+        .collect();
+    Json(upstreams)
+}
+
+async fn update_server(
+    AxumState(admin): AxumState<AdminState>,
+    Path((server, action)): Path<(String, Action)>,
+) -> Result<Json<ServerView>, StatusCode> {
+    let config = admin.config.borrow().clone();
+    let known = config
+        .upstreams
+        .values()
+        .any(|upstream| upstream.servers.contains(&server));
+    if !known {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let servers = &admin.state.servers;
+    match action {
+        Action::Enable => servers.set_status(&server, ServerStatus::Enabled),
+        Action::Drain => servers.set_status(&server, ServerStatus::Draining),
+        Action::Disable => {
+            servers.set_status(&server, ServerStatus::Disabled);
+            let closed = admin.state.active.close_upstream(&server);
+            info!(
+                "Closed {} connections to disabled server: {}",
+                closed, server
+            );
+        }
+    }
+    info!("Server {} is {:?}", server, servers.health(&server).status);
+    Ok(Json(admin.server_view(&server)))
+}
+
+impl AdminState {
+    fn server_view(&self, server: &str) -> ServerView {
+        ServerView {
+            address: server.to_string(),
+            health: self.state.servers.health(server),
+            active_connections: self.state.active.count_upstream(server),
+        }
+    }
+}
@@ -0,0 +1,566 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use ipnet::IpNet;
+use rustls::{ClientConfig, ServerConfig};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationSeconds};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+use tokio_rustls::TlsConnector;
+
+use crate::{
+    health::Servers,
+    http::{self, HttpClient},
+    proxy::ProxyOptions,
+    proxy_protocol::{self, Addresses},
+    tls::{self, MaybeTlsStream},
+};
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Config {
+    pub listeners: Vec<ListenerConfig>,
+    pub upstreams: HashMap<String, UpstreamConfig>,
+    /// read at startup only, a reload does not move the access log
+    #[serde(default)]
+    pub access_log: Option<AccessLogConfig>,
+    /// cap on the connections of all listeners together
+    #[serde(default)]
+    pub max_connections: Option<MaxConnections>,
+    /// read at startup only like the access log
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    /// export a span per connection, read at startup only
+    #[serde(default)]
+    pub otlp: Option<OtlpConfig>,
+    /// seconds the open connections get to finish on SIGTERM before they are force closed
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: Duration,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OtlpConfig {
+    /// gRPC endpoint of the collector
+    #[serde(default = "default_otlp_endpoint")]
+    pub endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminConfig {
+    /// no authentication, keep it on a private address
+    pub listen_addr: String,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct MaxConnections {
+    pub limit: usize,
+    #[serde(default)]
+    pub when_full: WhenFull,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WhenFull {
+    /// stop accepting until a connection is closed
+    #[default]
+    Wait,
+    /// accept and close new connections right away
+    Reject,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccessLogConfig {
+    pub directory: String,
+    #[serde(default = "default_access_log_prefix")]
+    pub file_name_prefix: String,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListenerConfig {
+    pub name: String,
+    pub listen_addr: String,
+    #[serde(default)]
+    pub mode: ListenerMode,
+    /// upstream pool used in tcp and udp mode, in tcp mode with `sni_routes` the fallback
+    /// for clients whose server name matches no route
+    #[serde(default)]
+    pub upstream: Option<String>,
+    /// pick the upstream by the SNI in the TLS ClientHello without terminating TLS, tcp mode only
+    #[serde(default)]
+    pub sni_routes: Vec<SniRoute>,
+    /// routing table used in http mode
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// terminate TLS for incoming connections
+    #[serde(default)]
+    pub tls: Option<ListenerTls>,
+    #[serde(skip)]
+    pub tls_config: Option<Arc<ServerConfig>>,
+    /// close a tcp connection or expire a udp session after this many seconds without traffic
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub idle_timeout: Option<Duration>,
+    /// close a tcp connection after this many seconds regardless of traffic
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub max_lifetime: Option<Duration>,
+    /// forward plaintext tcp connections with splice(2), Linux only, connections spliced while
+    /// the listener had no bandwidth limit stay unthrottled if a reload adds one
+    #[serde(default)]
+    pub splice: bool,
+    /// clients allowed to connect, everyone if empty
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// clients denied even if they are allowed
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+    #[serde(default)]
+    pub max_connections_per_ip: Option<usize>,
+    /// bytes per second in each direction shared by all connections, tcp mode only
+    #[serde(default)]
+    pub max_bytes_per_second: Option<u64>,
+    /// bytes per second in each direction shared by the connections of one client ip
+    #[serde(default)]
+    pub max_bytes_per_second_per_ip: Option<u64>,
+    /// expect a PROXY protocol header from a load balancer in front of minginx,
+    /// the client address in it is used for logs and ACL decisions
+    #[serde(default)]
+    pub accept_proxy_protocol: bool,
+    /// networks of the load balancers trusted to send a PROXY protocol header,
+    /// connections from anywhere else are rejected before the header is read
+    #[serde(default)]
+    pub proxy_protocol_from: Vec<IpNet>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListenerTls {
+    /// the first certificate is used when the client sends no or an unknown SNI
+    pub certificates: Vec<CertificateConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CertificateConfig {
+    /// SNI names served by this certificate, `*.example.com` matches one label
+    #[serde(default)]
+    pub server_names: Vec<String>,
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerMode {
+    #[default]
+    Tcp,
+    Http,
+    /// one session per client address, replies are sent back from the listener socket
+    Udp,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Route {
+    /// match the `Host` header, any host if not set
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default = "default_path_prefix")]
+    pub path_prefix: String,
+    /// replace the matched prefix before forwarding
+    #[serde(default)]
+    pub rewrite: Option<String>,
+    pub upstream: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SniRoute {
+    /// `*.example.com` matches one label
+    pub server_names: Vec<String>,
+    pub upstream: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpstreamConfig {
+    pub servers: Vec<String>,
+    /// re-encrypt the traffic toward the servers
+    #[serde(default)]
+    pub tls: Option<UpstreamTls>,
+    /// pass the client address on to the servers in a PROXY protocol header, tcp mode only
+    #[serde(default)]
+    pub proxy_protocol: Option<proxy_protocol::Version>,
+    #[serde(skip)]
+    cursor: AtomicUsize,
+    #[serde(skip)]
+    tls_config: Option<Arc<ClientConfig>>,
+    #[serde(skip)]
+    http_client: OnceLock<HttpClient>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpstreamTls {
+    /// name to verify the server certificate against, the server host by default
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// PEM bundle of trusted CAs, the webpki roots by default
+    #[serde(default)]
+    pub ca_file: Option<String>,
+}
+
+fn default_path_prefix() -> String {
+    "/".to_string()
+}
+
+fn default_access_log_prefix() -> String {
+    "minginx-access.log".to_string()
+}
+
+fn default_otlp_endpoint() -> String {
+    "http://127.0.0.1:4317".to_string()
+}
+
+fn default_service_name() -> String {
+    "minginx".to_string()
+}
+
+fn default_drain_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Can not read config file: {}", path.display()))?;
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut config: Config = serde_yaml::from_str(content)?;
+        config.prepare()?;
+        Ok(config)
+    }
+
+    /// validate the config and load the TLS material it refers to
+    fn prepare(&mut self) -> Result<()> {
+        self.validate()?;
+        for listener in &mut self.listeners {
+            if let Some(tls) = &listener.tls {
+                listener.tls_config = Some(tls::server_config(tls)?);
+            }
+        }
+        for upstream in self.upstreams.values_mut() {
+            if let Some(tls) = &upstream.tls {
+                for server in &upstream.servers {
+                    tls::server_name(tls, server)?;
+                }
+                upstream.tls_config = Some(tls::client_config(Some(tls))?);
+            }
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (name, upstream) in &self.upstreams {
+            if upstream.servers.is_empty() {
+                bail!("upstream {} has no servers", name);
+            }
+        }
+        for listener in &self.listeners {
+            let bandwidth = [
+                listener.max_bytes_per_second,
+                listener.max_bytes_per_second_per_ip,
+            ];
+            if bandwidth.contains(&Some(0)) {
+                bail!(
+                    "listener {}: bandwidth limits must be positive",
+                    listener.name
+                );
+            }
+            if listener.mode != ListenerMode::Tcp && bandwidth.iter().any(Option::is_some) {
+                bail!(
+                    "listener {}: bandwidth limits apply to tcp listeners only",
+                    listener.name
+                );
+            }
+            if listener.accept_proxy_protocol && listener.proxy_protocol_from.is_empty() {
+                bail!(
+                    "listener {}: accept_proxy_protocol needs the load balancer networks in proxy_protocol_from",
+                    listener.name
+                );
+            }
+            match listener.mode {
+                ListenerMode::Tcp => {
+                    match &listener.upstream {
+                        Some(upstream) => self.check_upstream(&listener.name, upstream)?,
+                        None if listener.sni_routes.is_empty() => {
+                            bail!("tcp listener {} has no upstream", listener.name)
+                        }
+                        None => {}
+                    }
+                    self.validate_sni_routes(listener)?;
+                }
+                ListenerMode::Udp | ListenerMode::Http if !listener.sni_routes.is_empty() => {
+                    bail!(
+                        "listener {}: sni routes apply to tcp listeners only",
+                        listener.name
+                    );
+                }
+                ListenerMode::Udp => {
+                    let Some(name) = &listener.upstream else {
+                        bail!("udp listener {} has no upstream", listener.name);
+                    };
+                    self.check_upstream(&listener.name, name)?;
+                    if listener.tls.is_some() || listener.accept_proxy_protocol {
+                        bail!(
+                            "udp listener {} supports neither TLS nor PROXY protocol",
+                            listener.name
+                        );
+                    }
+                    let upstream = &self.upstreams[name];
+                    if upstream.tls.is_some() || upstream.proxy_protocol.is_some() {
+                        bail!(
+                            "udp listener {} can not use upstream {} with TLS or PROXY protocol",
+                            listener.name,
+                            name
+                        );
+                    }
+                }
+                ListenerMode::Http => {
+                    if listener.routes.is_empty() {
+                        bail!("http listener {} has no routes", listener.name);
+                    }
+                    for route in &listener.routes {
+                        if !route.path_prefix.starts_with('/') {
+                            bail!(
+                                "listener {}: path prefix {} must start with '/'",
+                                listener.name,
+                                route.path_prefix
+                            );
+                        }
+                        self.check_upstream(&listener.name, &route.upstream)?;
+                        if self.upstreams[&route.upstream].proxy_protocol.is_some() {
+                            bail!(
+                                "http listener {} can not route to upstream {} which expects PROXY protocol",
+                                listener.name,
+                                route.upstream
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// listeners are bound once at startup, a reload may only change what is behind them
+    pub fn same_listeners(&self, other: &Config) -> bool {
+        self.listeners.len() == other.listeners.len()
+            && self.listeners.iter().zip(&other.listeners).all(|(a, b)| {
+                a.name == b.name && a.listen_addr == b.listen_addr && a.is_udp() == b.is_udp()
+            })
+    }
+
+    pub fn listener(&self, name: &str) -> Option<&ListenerConfig> {
+        self.listeners.iter().find(|l| l.name == name)
+    }
+
+    pub fn upstream(&self, name: &str) -> Option<&UpstreamConfig> {
+        self.upstreams.get(name)
+    }
+
+    /// the TLS of the client goes through untouched, so neither side may add its own
+    fn validate_sni_routes(&self, listener: &ListenerConfig) -> Result<()> {
+        if listener.sni_routes.is_empty() {
+            return Ok(());
+        }
+        if listener.tls.is_some() {
+            bail!(
+                "listener {}: sni routes can not be combined with tls",
+                listener.name
+            );
+        }
+        let upstreams = listener
+            .sni_routes
+            .iter()
+            .map(|route| &route.upstream)
+            .chain(&listener.upstream);
+        for upstream in upstreams {
+            self.check_upstream(&listener.name, upstream)?;
+            if self.upstreams[upstream].tls.is_some() {
+                bail!(
+                    "listener {}: sni route to upstream {} which uses tls",
+                    listener.name,
+                    upstream
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn check_upstream(&self, listener: &str, upstream: &str) -> Result<()> {
+        if !self.upstreams.contains_key(upstream) {
+            bail!(
+                "listener {} refers to unknown upstream {}",
+                listener,
+                upstream
+            );
+        }
+        Ok(())
+    }
+}
+
+impl ListenerConfig {
+    /// udp listeners are bound to a `UdpSocket` instead of a `TcpListener`
+    pub fn is_udp(&self) -> bool {
+        self.mode == ListenerMode::Udp
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let denied = self.deny.iter().any(|net| net.contains(&ip));
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip));
+        allowed && !denied
+    }
+
+    /// a PROXY protocol header is only believed from a trusted load balancer
+    pub fn trusts_proxy_protocol_from(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.proxy_protocol_from.iter().any(|net| net.contains(&ip))
+    }
+
+    pub fn proxy_options(&self) -> ProxyOptions {
+        ProxyOptions {
+            idle_timeout: self.idle_timeout,
+            max_lifetime: self.max_lifetime,
+            splice: self.splice,
+        }
+    }
+
+    /// the upstream for the server name in a ClientHello, exact names first then `*.` wildcards,
+    /// falling back to `upstream`
+    pub fn sni_upstream(&self, server_name: Option<&str>) -> Option<&str> {
+        let matching = |name: &str| {
+            self.sni_routes.iter().find(|route| {
+                route
+                    .server_names
+                    .iter()
+                    .any(|n| n.eq_ignore_ascii_case(name))
+            })
+        };
+        let route = server_name.and_then(|name| {
+            let wildcard = name
+                .split_once('.')
+                .map(|(_, parent)| format!("*.{}", parent));
+            matching(name).or_else(|| wildcard.and_then(|wildcard| matching(&wildcard)))
+        });
+        route
+            .map(|route| route.upstream.as_str())
+            .or(self.upstream.as_deref())
+    }
+
+    /// find the route for a request, the longest matching path prefix wins
+    pub fn route(&self, host: Option<&str>, path: &str) -> Option<&Route> {
+        self.routes
+            .iter()
+            .filter(|route| route.matches(host, path))
+            .max_by_key(|route| route.path_prefix.len())
+    }
+}
+
+impl Route {
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        let host_matches = match (&self.host, host) {
+            (None, _) => true,
+            (Some(expected), Some(host)) => expected.eq_ignore_ascii_case(host),
+            (Some(_), None) => false,
+        };
+        host_matches && self.strip_prefix(path).is_some()
+    }
+
+    /// strip the prefix only on a path segment boundary, `/api` matches `/api/v1` but not `/apis`
+    fn strip_prefix<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let prefix = self.path_prefix.trim_end_matches('/');
+        let rest = path.strip_prefix(prefix)?;
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+
+    pub fn rewrite_path(&self, path: &str) -> String {
+        let (Some(rewrite), Some(rest)) = (&self.rewrite, self.strip_prefix(path)) else {
+            return path.to_string();
+        };
+        let path = format!("{}{}", rewrite.trim_end_matches('/'), rest);
+        if path.is_empty() {
+            "/".to_string()
+        } else {
+            path
+        }
+    }
+}
+
+impl UpstreamConfig {
+    pub async fn connect(&self, addr: &str, client: &Addresses) -> Result<MaybeTlsStream> {
+        let mut stream = TcpStream::connect(addr).await?;
+        if let Some(version) = self.proxy_protocol {
+            stream
+                .write_all(&proxy_protocol::encode(version, client))
+                .await?;
+        }
+        match (&self.tls, &self.tls_config) {
+            (Some(tls), Some(tls_config)) => {
+                let connector = TlsConnector::from(tls_config.clone());
+                let stream = connector
+                    .connect(tls::server_name(tls, addr)?, stream)
+                    .await?;
+                Ok(MaybeTlsStream::Tls(Box::new(stream.into())))
+            }
+            _ => Ok(MaybeTlsStream::Plain(stream)),
+        }
+    }
+
+    pub fn scheme(&self) -> &'static str {
+        if self.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        }
+    }
+
+    pub fn http_client(&self) -> Result<&HttpClient> {
+        if let Some(client) = self.http_client.get() {
+            return Ok(client);
+        }
+        let server_name = match &self.tls {
+            Some(tls) if tls.server_name.is_some() => Some(tls::server_name(tls, "")?),
+            _ => None,
+        };
+        let tls_config = match &self.tls_config {
+            Some(tls_config) => tls_config.clone(),
+            None => tls::client_config(None)?,
+        };
+        Ok(self
+            .http_client
+            .get_or_init(|| http::client(tls_config, server_name)))
+    }
+
+    /// pick the next available server in round-robin order, skip drained and disabled ones
+    pub fn next_server(&self, servers: &Servers) -> Option<&str> {
+        (0..self.servers.len())
+            .map(|_| self.cursor.fetch_add(1, Ordering::Relaxed))
+            .map(|idx| self.servers[idx % self.servers.len()].as_str())
+            .find(|server| servers.available(server))
+    }
+}
@@ -0,0 +1,80 @@
+use std::fmt::Display;
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+/// connect failures in a row before a server is reported unhealthy
+const MAX_FAILURES: u32 = 3;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerStatus {
+    #[default]
+    Enabled,
+    /// no new connections, the open ones are left alone
+    Draining,
+    /// no new connections, the open ones are closed
+    Disabled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerHealth {
+    pub status: ServerStatus,
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+}
+
+/// status and passive health of the upstream servers by address,
+/// kept across config reloads so a drained server stays drained
+#[derive(Debug, Default)]
+pub struct Servers {
+    servers: DashMap<String, ServerHealth>,
+}
+
+impl Servers {
+    /// whether new connections may go to the server
+    pub fn available(&self, server: &str) -> bool {
+        self.servers
+            .get(server)
+            .is_none_or(|health| health.status == ServerStatus::Enabled)
+    }
+
+    pub fn health(&self, server: &str) -> ServerHealth {
+        self.servers
+            .get(server)
+            .map(|health| health.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn set_status(&self, server: &str, status: ServerStatus) {
+        self.servers.entry(server.to_string()).or_default().status = status;
+    }
+
+    /// update the health from the result of a connection attempt
+    pub fn record<T, E: Display>(&self, server: &str, res: &Result<T, E>) {
+        let mut health = self.servers.entry(server.to_string()).or_default();
+        match res {
+            Ok(_) => {
+                health.consecutive_failures = 0;
+                health.healthy = true;
+            }
+            Err(e) => {
+                health.consecutive_failures += 1;
+                health.healthy = health.consecutive_failures < MAX_FAILURES;
+                health.last_error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+impl Default for ServerHealth {
+    fn default() -> Self {
+        Self {
+            status: ServerStatus::default(),
+            healthy: true,
+            consecutive_failures: 0,
+            last_error: None,
+        }
+    }
+}
@@ -0,0 +1,384 @@
+use std::{
+    convert::Infallible,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http::{
+    header::{self, HeaderName},
+    uri::Authority,
+    HeaderMap, HeaderValue, Request, Response, StatusCode, Uri,
+};
+use http_body_util::{combinators::BoxBody, BodyExt, Empty};
+use hyper::{
+    body::{Body, Frame, Incoming, SizeHint},
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_rustls::{FixedServerNameResolver, HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::{TokioExecutor, TokioIo},
+};
+use rustls::{pki_types::ServerName, ClientConfig};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::{error, info, warn};
+
+use crate::{
+    access_log::RequestLog,
+    config::Config,
+    proxy::CloseReason,
+    state::{ActiveConnection, State},
+    telemetry,
+    tls::MaybeTlsStream,
+};
+
+pub type HttpClient = Client<HttpsConnector<HttpConnector>, ProxyBody>;
+
+type ProxyBody = BoxBody<Bytes, hyper::Error>;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+
+const HOP_BY_HOP_HEADERS: [HeaderName; 8] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// client shared by all connections to an upstream, keeps idle upstream connections alive for reuse
+pub fn client(
+    tls_config: Arc<ClientConfig>,
+    server_name: Option<ServerName<'static>>,
+) -> HttpClient {
+    let builder = HttpsConnectorBuilder::new()
+        .with_tls_config((*tls_config).clone())
+        .https_or_http();
+    let builder = match server_name {
+        Some(server_name) => {
+            builder.with_server_name_resolver(FixedServerNameResolver::new(server_name))
+        }
+        None => builder,
+    };
+    Client::builder(TokioExecutor::new())
+        .pool_idle_timeout(Duration::from_secs(90))
+        .build(builder.enable_http1().build())
+}
+
+pub async fn handle_http(
+    config: Arc<Config>,
+    state: Arc<State>,
+    connection: Arc<ActiveConnection>,
+    stream: MaybeTlsStream,
+) {
+    let addr = connection.client();
+    let proto = if stream.is_tls() { "https" } else { "http" };
+    let stream = Counted {
+        stream,
+        connection: connection.clone(),
+    };
+    let shutdown = state.shutdown.clone();
+    let service = {
+        let connection = connection.clone();
+        service_fn(move |req| {
+            forward(
+                config.clone(),
+                state.clone(),
+                connection.clone(),
+                proto,
+                req,
+            )
+        })
+    };
+    let serve = http1::Builder::new()
+        .keep_alive(true)
+        .serve_connection(TokioIo::new(stream), service);
+    tokio::pin!(serve);
+    let mut draining = false;
+    let res = loop {
+        tokio::select! {
+            res = serve.as_mut() => break res,
+            _ = shutdown.cancelled(), if !draining => {
+                // finish the request in flight, then close instead of keeping the connection alive
+                serve.as_mut().graceful_shutdown();
+                draining = true;
+            },
+            reason = connection.closed() => {
+                let reason = CloseReason::from(reason);
+                info!("Close http connection from {}: {}", addr, reason);
+                telemetry::record_close(&connection, Some(&reason));
+                return;
+            },
+        }
+    };
+    if let Err(e) = res {
+        error!("Error serving http connection from {}: {:?}", addr, e);
+    }
+    telemetry::record_close(&connection, None);
+}
+
+/// proxy one request, its access record is written once the response body is done
+async fn forward(
+    config: Arc<Config>,
+    state: Arc<State>,
+    connection: Arc<ActiveConnection>,
+    proto: &'static str,
+    req: Request<Incoming>,
+) -> Result<Response<ProxyBody>, Infallible> {
+    let mut log = RequestLog::new(
+        connection.client(),
+        &connection.listener,
+        req.method(),
+        req.uri().path(),
+    );
+    let bytes = log.request_bytes.clone();
+    let req = req.map(|body| CountedBody::new(body, bytes, None).boxed());
+    let res = forward_request(&config, &state, &connection, proto, req, &mut log).await;
+    log.set_status(res.status());
+    let bytes = log.response_bytes.clone();
+    Ok(res.map(|body| CountedBody::new(body, bytes, Some(log)).boxed()))
+}
+
+async fn forward_request(
+    config: &Config,
+    state: &State,
+    connection: &ActiveConnection,
+    proto: &'static str,
+    mut req: Request<ProxyBody>,
+    log: &mut RequestLog,
+) -> Response<ProxyBody> {
+    let addr = connection.client();
+    let Some(listener) = config.listener(&connection.listener) else {
+        error!("Listener {} is not configured", connection.listener);
+        return status(StatusCode::BAD_GATEWAY);
+    };
+    let host = request_host(&req);
+    let path = req.uri().path();
+    let Some(route) = listener.route(host.as_deref(), path) else {
+        warn!("No route for {:?}{} from {}", host, path, addr);
+        return status(StatusCode::NOT_FOUND);
+    };
+    let Some(upstream) = config.upstream(&route.upstream) else {
+        error!("Upstream {} is not configured", route.upstream);
+        return status(StatusCode::BAD_GATEWAY);
+    };
+    let client = match upstream.http_client() {
+        Ok(client) => client,
+        Err(e) => {
+            error!(
+                "Error create client for upstream {}: {:?}",
+                route.upstream, e
+            );
+            return status(StatusCode::BAD_GATEWAY);
+        }
+    };
+    let Some(upstream_addr) = upstream.next_server(&state.servers) else {
+        warn!("No available server in upstream: {}", route.upstream);
+        return status(StatusCode::SERVICE_UNAVAILABLE);
+    };
+    connection.set_upstream(upstream_addr);
+    log.set_upstream(upstream_addr);
+
+    let mut path_and_query = route.rewrite_path(path);
+    if let Some(query) = req.uri().query() {
+        path_and_query = format!("{}?{}", path_and_query, query);
+    }
+    let uri = match format!(
+        "{}://{}{}",
+        upstream.scheme(),
+        upstream_addr,
+        path_and_query
+    )
+    .parse::<Uri>()
+    {
+        Ok(uri) => uri,
+        Err(e) => {
+            warn!("Invalid upstream uri for {}: {:?}", path_and_query, e);
+            return status(StatusCode::BAD_REQUEST);
+        }
+    };
+    info!(
+        "Proxy {} {} from {} to upstream: {}{}",
+        req.method(),
+        req.uri(),
+        addr,
+        upstream_addr,
+        uri.path()
+    );
+    *req.uri_mut() = uri;
+
+    let headers = req.headers_mut();
+    remove_hop_by_hop_headers(headers);
+    set_forwarded_headers(headers, addr, proto);
+
+    let res = client.request(req).await;
+    state.servers.record(upstream_addr, &res);
+    match res {
+        Ok(res) => {
+            let (mut parts, body) = res.into_parts();
+            remove_hop_by_hop_headers(&mut parts.headers);
+            Response::from_parts(parts, body.boxed())
+        }
+        Err(e) => {
+            error!("Error request upstream {}: {:?}", upstream_addr, e);
+            status(StatusCode::BAD_GATEWAY)
+        }
+    }
+}
+
+/// host without port, from the absolute-form uri or the `Host` header
+fn request_host<B>(req: &Request<B>) -> Option<String> {
+    if let Some(host) = req.uri().host() {
+        return Some(host.to_string());
+    }
+    let host = req.headers().get(header::HOST)?.to_str().ok()?;
+    let authority = host.parse::<Authority>().ok()?;
+    Some(authority.host().to_string())
+}
+
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    // headers listed in `Connection` are hop-by-hop as well
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed.iter().chain(HOP_BY_HOP_HEADERS.iter()) {
+        headers.remove(name);
+    }
+}
+
+fn set_forwarded_headers(headers: &mut HeaderMap, addr: SocketAddr, proto: &'static str) {
+    let ip = addr.ip().to_string();
+    let forwarded_for = match headers
+        .get(&X_FORWARDED_FOR)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(prev) => format!("{}, {}", prev, ip),
+        None => ip,
+    };
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+}
+
+/// the client side of a connection, counts the bytes of requests and responses
+struct Counted {
+    stream: MaybeTlsStream,
+    connection: Arc<ActiveConnection>,
+}
+
+impl AsyncRead for Counted {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.stream).poll_read(cx, buf);
+        let n = buf.filled().len() - filled;
+        self.connection
+            .traffic
+            .client_to_upstream
+            .fetch_add(n as u64, Ordering::Relaxed);
+        res
+    }
+}
+
+impl AsyncWrite for Counted {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.connection
+                .traffic
+                .upstream_to_client
+                .fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// a request or response body that counts its bytes, a response body writes the access record
+/// of its request when it is dropped
+struct CountedBody<B> {
+    body: B,
+    bytes: Arc<AtomicU64>,
+    log: Option<RequestLog>,
+}
+
+impl<B> CountedBody<B> {
+    fn new(body: B, bytes: Arc<AtomicU64>, log: Option<RequestLog>) -> Self {
+        Self { body, bytes, log }
+    }
+}
+
+impl<B> Body for CountedBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let res = Pin::new(&mut self.body).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &res {
+            if let Some(data) = frame.data_ref() {
+                self.bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+        }
+        res
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl<B> Drop for CountedBody<B> {
+    fn drop(&mut self) {
+        if let Some(log) = self.log.take() {
+            log.finish();
+        }
+    }
+}
+
+fn status(code: StatusCode) -> Response<ProxyBody> {
+    let mut res = Response::new(Empty::new().map_err(|never| match never {}).boxed());
+    *res.status_mut() = code;
+    res
+}
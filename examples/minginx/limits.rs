@@ -0,0 +1,110 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use dashmap::DashMap;
+use tokio::sync::Notify;
+
+/// connection counters shared by all listeners, they outlive config reloads
+#[derive(Debug, Default)]
+pub struct Connections {
+    total: AtomicUsize,
+    /// by listener, as `max_connections_per_ip` is set per listener
+    per_ip: DashMap<(String, IpAddr), usize>,
+    released: Notify,
+}
+
+/// a slot in the global count, freed on drop
+#[derive(Debug)]
+pub struct Slot {
+    connections: Arc<Connections>,
+}
+
+/// a slot in the per client count, freed on drop
+#[derive(Debug)]
+pub struct IpSlot {
+    connections: Arc<Connections>,
+    key: (String, IpAddr),
+}
+
+impl Connections {
+    /// wait until the number of connections is below `limit` without taking a slot
+    pub async fn wait_below(&self, limit: Option<usize>) {
+        let limit = limit.unwrap_or(usize::MAX);
+        loop {
+            // created before the check so a release in between is not missed
+            let released = self.released.notified();
+            if self.total() < limit {
+                return;
+            }
+            released.await;
+        }
+    }
+
+    pub async fn acquire(self: &Arc<Self>, limit: Option<usize>) -> Slot {
+        loop {
+            let released = self.released.notified();
+            if let Some(slot) = self.try_acquire(limit) {
+                return slot;
+            }
+            released.await;
+        }
+    }
+
+    pub fn try_acquire(self: &Arc<Self>, limit: Option<usize>) -> Option<Slot> {
+        let limit = limit.unwrap_or(usize::MAX);
+        self.total
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |total| {
+                (total < limit).then_some(total + 1)
+            })
+            .ok()?;
+        Some(Slot {
+            connections: self.clone(),
+        })
+    }
+
+    /// an IPv4-mapped IPv6 address counts as the IPv4 client it is
+    pub fn try_acquire_ip(
+        self: &Arc<Self>,
+        listener: &str,
+        ip: IpAddr,
+        limit: Option<usize>,
+    ) -> Option<IpSlot> {
+        let key = (listener.to_string(), ip.to_canonical());
+        let mut count = self.per_ip.entry(key.clone()).or_insert(0);
+        if *count >= limit.unwrap_or(usize::MAX) {
+            return None;
+        }
+        *count += 1;
+        Some(IpSlot {
+            connections: self.clone(),
+            key,
+        })
+    }
+
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.connections.total.fetch_sub(1, Ordering::AcqRel);
+        self.connections.released.notify_waiters();
+    }
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        if let Some(mut count) = self.connections.per_ip.get_mut(&self.key) {
+            *count -= 1;
+        }
+        self.connections
+            .per_ip
+            .remove_if(&self.key, |_, count| *count == 0);
+    }
+}
@@ -0,0 +1,149 @@
+mod access_log;
+mod admin;
+mod config;
+mod health;
+mod http;
+mod limits;
+mod proxy;
+mod proxy_protocol;
+mod reload;
+mod server;
+mod sni;
+#[cfg(target_os = "linux")]
+mod splice;
+mod state;
+mod telemetry;
+mod throttle;
+mod tls;
+mod udp;
+
+#[cfg(test)]
+mod tests;
+
+use std::{env, sync::Arc, time::Duration};
+
+use anyhow::{bail, Result};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    task::JoinSet,
+    time::timeout,
+};
+use tracing::{info, level_filters::LevelFilter, warn};
+use tracing_subscriber::{
+    fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt, Layer,
+};
+
+use crate::{
+    config::Config,
+    reload::Reloader,
+    server::Server,
+    state::{ForceClose, State},
+};
+
+const CONFIG_PATH: &str = "examples/minginx/minginx.yml";
+
+const FORCE_CLOSE_GRACE: Duration = Duration::from_secs(1);
+
+fn resolve_config_path() -> String {
+    env::args()
+        .nth(1)
+        .unwrap_or_else(|| CONFIG_PATH.to_string())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let config_path = resolve_config_path();
+    let config = Arc::new(Config::load(&config_path)?);
+
+    let console = tracing_subscriber::fmt::Layer::new()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_filter(LevelFilter::INFO);
+
+    let (access_log, _guard) = match &config.access_log {
+        Some(access_log_config) => {
+            let (layer, guard) = access_log::layer(access_log_config);
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    let telemetry = match &config.otlp {
+        Some(otlp_config) => {
+            let tracer = telemetry::init_tracer(otlp_config)?;
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(console)
+        .with(access_log)
+        .with(telemetry)
+        .init();
+
+    info!("Loaded config from: {}", config_path);
+    let (sender, receiver) = watch::channel(config.clone());
+    let state = Arc::new(State::default());
+
+    let mut servers = JoinSet::new();
+    let server = Server::bind(receiver.clone(), state.clone()).await?;
+    servers.spawn(server.run());
+    let reloader = Arc::new(Reloader::new(config_path, sender));
+    if let Some(admin_config) = &config.admin {
+        let listener = TcpListener::bind(&admin_config.listen_addr).await?;
+        info!("Admin api listen_addr: {}", admin_config.listen_addr);
+        servers.spawn(admin::serve(
+            listener,
+            receiver.clone(),
+            state.clone(),
+            reloader.clone(),
+        ));
+    }
+    servers.spawn(reloader.run());
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let res = tokio::select! {
+        res = join_all(&mut servers) => res,
+        _ = terminate.recv() => {
+            info!("Received SIGTERM, stop accepting connections");
+            let drain_timeout = receiver.borrow().drain_timeout;
+            drain(&state, drain_timeout).await
+        }
+    };
+    if config.otlp.is_some() {
+        // export the spans still in the batch
+        opentelemetry::global::shutdown_tracer_provider();
+    }
+    res
+}
+
+async fn join_all(servers: &mut JoinSet<Result<()>>) -> Result<()> {
+    while let Some(res) = servers.join_next().await {
+        res??;
+    }
+    Ok(())
+}
+
+/// let the open connections finish within `drain_timeout`, fail if some had to be force closed
+async fn drain(state: &State, drain_timeout: Duration) -> Result<()> {
+    state.shutdown.cancel();
+    info!(
+        "Draining {} connections for up to {:?}",
+        state.active.len(),
+        drain_timeout
+    );
+    if timeout(drain_timeout, state.active.wait_empty())
+        .await
+        .is_ok()
+    {
+        info!("All connections drained, shut down");
+        return Ok(());
+    }
+    let closed = state.active.close_all(ForceClose::Shutdown);
+    warn!("Drain timeout expired, force closed {} connections", closed);
+    // give the closed connections a moment to write their access records
+    let _ = timeout(FORCE_CLOSE_GRACE, state.active.wait_empty()).await;
+    bail!("Shut down with {} connections force closed", closed)
+}
@@ -0,0 +1,402 @@
+use std::{
+    fmt,
+    future::{pending, Future},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::{sleep, sleep_until, timeout, Instant},
+};
+use tracing::{error, info, Span};
+
+#[cfg(target_os = "linux")]
+use tokio::net::TcpStream;
+
+#[cfg(target_os = "linux")]
+use crate::splice;
+use crate::{
+    access_log::AccessLog,
+    config::{Config, ListenerConfig, UpstreamConfig},
+    proxy_protocol::Addresses,
+    sni::{self, ClientHello},
+    state::{ActiveConnection, ForceClose, State},
+    telemetry,
+    throttle::{Direction, Throttle},
+    tls::MaybeTlsStream,
+};
+
+const BUF_SIZE: usize = 8 * 1024;
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// bytes copied so far, updated while the connection is open
+#[derive(Debug, Default, Serialize)]
+pub struct Traffic {
+    #[serde(rename = "client_to_upstream_bytes")]
+    pub client_to_upstream: AtomicU64,
+    #[serde(rename = "upstream_to_client_bytes")]
+    pub upstream_to_client: AtomicU64,
+}
+
+#[derive(Debug)]
+pub enum CloseReason {
+    ClientEof,
+    UpstreamEof,
+    IdleTimeout,
+    MaxLifetime,
+    /// the upstream server was disabled through the admin api
+    UpstreamDisabled,
+    /// force closed at the end of the drain on shutdown
+    Shutdown,
+    /// refused by the listener ACL or the per client limit
+    Rejected(anyhow::Error),
+    Error(anyhow::Error),
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProxyOptions {
+    /// no bytes in either direction
+    pub idle_timeout: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+    /// use the splice(2) fast path when neither side is TLS, Linux only
+    pub splice: bool,
+}
+
+/// time of the last byte copied in either direction, in millis since the start
+#[derive(Debug)]
+pub struct Activity {
+    start: Instant,
+    last: AtomicU64,
+}
+
+pub async fn handle_tcp(
+    config: Arc<Config>,
+    state: &State,
+    connection: &ActiveConnection,
+    mut stream: MaybeTlsStream,
+    client: Addresses,
+    mut access_log: AccessLog,
+) {
+    let addr = client.source;
+    let listener = connection.listener.as_str();
+    let Route {
+        listener_config,
+        client_hello,
+        upstream,
+        upstream_addr,
+    } = match route(&config, state, listener, &mut stream).await {
+        Ok(route) => route,
+        Err(e) => {
+            error!("Error routing connection from {}: {:?}", addr, e);
+            let close_reason = CloseReason::Error(e);
+            telemetry::record_close(connection, Some(&close_reason));
+            access_log.finish(connection, &close_reason);
+            return;
+        }
+    };
+    connection.set_upstream(upstream_addr);
+    access_log.set_upstream(upstream_addr);
+    info!("Proxy {} to upstream: {}", addr, upstream_addr);
+    let res = upstream.connect(upstream_addr, &client).await;
+    state.servers.record(upstream_addr, &res);
+    let close_reason = match res {
+        Ok(mut upstream) => {
+            let replayed = replay(&mut upstream, client_hello, &connection.traffic).await;
+            let options = listener_config.proxy_options();
+            let throttle = state.bandwidth.throttle(listener_config, addr.ip());
+            let proxy = proxy(stream, upstream, options, &connection.traffic, &throttle);
+            let close_reason = match replayed {
+                Ok(()) => tokio::select! {
+                    close_reason = proxy => close_reason,
+                    reason = connection.closed() => reason.into(),
+                },
+                Err(e) => CloseReason::Error(e),
+            };
+            if let CloseReason::Error(e) = &close_reason {
+                error!("Error proxy data: {:?}", e);
+            }
+            close_reason
+        }
+        Err(e) => {
+            error!("Error connect to upstream: {:?}", e);
+            CloseReason::Error(e)
+        }
+    };
+    telemetry::record_close(connection, Some(&close_reason));
+    access_log.finish(connection, &close_reason);
+}
+
+/// the upstream server picked for a connection, by the server name in its ClientHello
+/// if the listener has SNI routes
+struct Route<'a> {
+    listener_config: &'a ListenerConfig,
+    client_hello: Option<ClientHello>,
+    upstream: &'a UpstreamConfig,
+    upstream_addr: &'a str,
+}
+
+async fn route<'a>(
+    config: &'a Config,
+    state: &State,
+    listener: &str,
+    stream: &mut MaybeTlsStream,
+) -> Result<Route<'a>> {
+    let listener_config = config
+        .listener(listener)
+        .with_context(|| format!("Listener {} is not configured", listener))?;
+    let client_hello = if listener_config.sni_routes.is_empty() {
+        None
+    } else {
+        let client_hello = timeout(CLIENT_HELLO_TIMEOUT, sni::read_client_hello(stream))
+            .await
+            .context("Timed out reading ClientHello")?
+            .context("Error reading ClientHello")?;
+        Some(client_hello)
+    };
+    let server_name = client_hello
+        .as_ref()
+        .and_then(|client_hello| client_hello.server_name.as_deref());
+    if let Some(server_name) = server_name {
+        Span::current().record("server_name", server_name);
+    }
+    let (name, upstream) = listener_config
+        .sni_upstream(server_name)
+        .and_then(|name| Some((name, config.upstream(name)?)))
+        .with_context(|| {
+            format!(
+                "No upstream configured for listener {}, server name: {:?}",
+                listener, server_name
+            )
+        })?;
+    let upstream_addr = upstream
+        .next_server(&state.servers)
+        .with_context(|| format!("No available server in upstream: {}", name))?;
+    Ok(Route {
+        listener_config,
+        client_hello,
+        upstream,
+        upstream_addr,
+    })
+}
+
+/// send the bytes read to route the connection before anything else
+async fn replay(
+    upstream: &mut MaybeTlsStream,
+    client_hello: Option<ClientHello>,
+    traffic: &Traffic,
+) -> Result<()> {
+    let Some(client_hello) = client_hello else {
+        return Ok(());
+    };
+    upstream.write_all(&client_hello.bytes).await?;
+    traffic
+        .client_to_upstream
+        .fetch_add(client_hello.bytes.len() as u64, Ordering::Relaxed);
+    Ok(())
+}
+
+/// copy data both ways until both sides reach EOF, either side fails or a timeout expires,
+/// the EOF of one side is passed on to the other as a half-close
+pub async fn proxy(
+    client: MaybeTlsStream,
+    upstream: MaybeTlsStream,
+    options: ProxyOptions,
+    traffic: &Traffic,
+    throttle: &Throttle,
+) -> CloseReason {
+    // splice can not wait for the bandwidth limit, throttled connections copy in userspace
+    #[cfg(target_os = "linux")]
+    if options.splice && throttle.is_unlimited() {
+        if let (MaybeTlsStream::Plain(client), MaybeTlsStream::Plain(upstream)) =
+            (&client, &upstream)
+        {
+            return proxy_splice(client, upstream, options, traffic).await;
+        }
+    }
+
+    let (mut client_reader, mut client_writer) = tokio::io::split(client);
+    let (mut upstream_reader, mut upstream_writer) = tokio::io::split(upstream);
+
+    let activity = Activity::new();
+    let client_to_upstream = copy(
+        &mut client_reader,
+        &mut upstream_writer,
+        &traffic.client_to_upstream,
+        &activity,
+        throttle,
+        Direction::ClientToUpstream,
+    );
+    let upstream_to_client = copy(
+        &mut upstream_reader,
+        &mut client_writer,
+        &traffic.upstream_to_client,
+        &activity,
+        throttle,
+        Direction::UpstreamToClient,
+    );
+    forward(client_to_upstream, upstream_to_client, &activity, options).await
+}
+
+/// the same as `proxy`, but the bytes never leave the kernel
+#[cfg(target_os = "linux")]
+async fn proxy_splice(
+    client: &TcpStream,
+    upstream: &TcpStream,
+    options: ProxyOptions,
+    traffic: &Traffic,
+) -> CloseReason {
+    let activity = &Activity::new();
+    let client_to_upstream = async move {
+        splice::copy(client, upstream, |n| {
+            traffic
+                .client_to_upstream
+                .fetch_add(n as u64, Ordering::Relaxed);
+            activity.touch();
+        })
+        .await
+        .map_err(Into::into)
+    };
+    let upstream_to_client = async move {
+        splice::copy(upstream, client, |n| {
+            traffic
+                .upstream_to_client
+                .fetch_add(n as u64, Ordering::Relaxed);
+            activity.touch();
+        })
+        .await
+        .map_err(Into::into)
+    };
+    forward(client_to_upstream, upstream_to_client, activity, options).await
+}
+
+/// drive both directions until the connection is done and tell why
+async fn forward(
+    client_to_upstream: impl Future<Output = Result<()>>,
+    upstream_to_client: impl Future<Output = Result<()>>,
+    activity: &Activity,
+    options: ProxyOptions,
+) -> CloseReason {
+    let idle = activity.idle(options.idle_timeout);
+    let max_lifetime = async {
+        match options.max_lifetime {
+            Some(max_lifetime) => sleep(max_lifetime).await,
+            None => pending().await,
+        }
+    };
+    tokio::pin!(client_to_upstream, upstream_to_client, idle, max_lifetime);
+
+    let mut client_eof = false;
+    let mut upstream_eof = false;
+    loop {
+        tokio::select! {
+            res = &mut client_to_upstream, if !client_eof => match res {
+                Ok(()) if upstream_eof => break CloseReason::UpstreamEof,
+                Ok(()) => client_eof = true,
+                Err(e) => break CloseReason::Error(e),
+            },
+            res = &mut upstream_to_client, if !upstream_eof => match res {
+                Ok(()) if client_eof => break CloseReason::ClientEof,
+                Ok(()) => upstream_eof = true,
+                Err(e) => break CloseReason::Error(e),
+            },
+            _ = &mut idle => break CloseReason::IdleTimeout,
+            _ = &mut max_lifetime => break CloseReason::MaxLifetime,
+        }
+    }
+}
+
+/// like `tokio::io::copy`, but the bytes copied so far are kept when it fails,
+/// and the writer is shut down once the reader reaches EOF
+async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    copied: &AtomicU64,
+    activity: &Activity,
+    throttle: &Throttle,
+    direction: Direction,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; BUF_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            writer.shutdown().await?;
+            return Ok(());
+        }
+        throttle.consume(direction, n).await;
+        writer.write_all(&buf[..n]).await?;
+        copied.fetch_add(n as u64, Ordering::Relaxed);
+        activity.touch();
+    }
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    pub fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// resolve once nothing was copied for `timeout`, never if there is no timeout
+    pub async fn idle(&self, timeout: Option<Duration>) {
+        let Some(timeout) = timeout else {
+            return pending().await;
+        };
+        loop {
+            let last = self.start + Duration::from_millis(self.last.load(Ordering::Relaxed));
+            let deadline = last + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            sleep_until(deadline).await;
+        }
+    }
+}
+
+impl CloseReason {
+    pub fn error(&self) -> Option<String> {
+        match self {
+            Self::Rejected(e) | Self::Error(e) => Some(e.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl From<ForceClose> for CloseReason {
+    fn from(reason: ForceClose) -> Self {
+        match reason {
+            ForceClose::UpstreamDisabled => Self::UpstreamDisabled,
+            ForceClose::Shutdown => Self::Shutdown,
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ClientEof => write!(f, "client_eof"),
+            Self::UpstreamEof => write!(f, "upstream_eof"),
+            Self::IdleTimeout => write!(f, "idle_timeout"),
+            Self::MaxLifetime => write!(f, "max_lifetime"),
+            Self::UpstreamDisabled => write!(f, "upstream_disabled"),
+            Self::Shutdown => write!(f, "shutdown"),
+            Self::Rejected(_) => write!(f, "rejected"),
+            Self::Error(_) => write!(f, "error"),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// the longest v1 header, including the trailing CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// the longest v2 address block with TLVs accepted, load balancers send far less
+const V2_MAX_LEN: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Version {
+    V1,
+    V2,
+}
+
+/// the two ends of the original client connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Addresses {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+pub fn encode(version: Version, addresses: &Addresses) -> Vec<u8> {
+    let (source, destination) = addresses.same_family();
+    match version {
+        Version::V1 => {
+            let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        Version::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            // version 2, PROXY command
+            header.push(0x21);
+            match (source.ip(), destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    header.push(0x11);
+                    header.extend_from_slice(&12u16.to_be_bytes());
+                    header.extend_from_slice(&src.octets());
+                    header.extend_from_slice(&dst.octets());
+                }
+                (src, dst) => {
+                    header.push(0x21);
+                    header.extend_from_slice(&36u16.to_be_bytes());
+                    header.extend_from_slice(&to_ipv6(src).octets());
+                    header.extend_from_slice(&to_ipv6(dst).octets());
+                }
+            }
+            header.extend_from_slice(&source.port().to_be_bytes());
+            header.extend_from_slice(&destination.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+/// read a v1 or v2 header without consuming anything after it,
+/// `None` means the sender did not pass on a client address (LOCAL or UNKNOWN)
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Addresses>> {
+    let mut prefix = [0; 12];
+    reader.read_exact(&mut prefix).await?;
+    if prefix == V2_SIGNATURE {
+        read_v2(reader).await
+    } else if prefix.starts_with(b"PROXY ") {
+        read_v1(reader, prefix).await
+    } else {
+        bail!("Missing PROXY protocol header")
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    prefix: [u8; 12],
+) -> Result<Option<Addresses>> {
+    let mut line = prefix.to_vec();
+    // byte by byte, the bytes after the CRLF belong to the proxied stream
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            bail!("PROXY protocol v1 header is too long");
+        }
+        line.push(reader.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, dst, src_port, dst_port] => Ok(Some(Addresses {
+            source: SocketAddr::new(src.parse()?, src_port.parse()?),
+            destination: SocketAddr::new(dst.parse()?, dst_port.parse()?),
+        })),
+        _ => bail!("Invalid PROXY protocol v1 header: {}", line),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Addresses>> {
+    let version_command = reader.read_u8().await?;
+    if version_command >> 4 != 2 {
+        bail!(
+            "Unsupported PROXY protocol version: {}",
+            version_command >> 4
+        );
+    }
+    let family = reader.read_u8().await?;
+    let len = reader.read_u16().await? as usize;
+    if len > V2_MAX_LEN {
+        bail!("PROXY protocol v2 header is too long: {} bytes", len);
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    // LOCAL command, e.g. health checks of the load balancer itself
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+    let addresses = match family {
+        0x11 => {
+            let payload = payload
+                .get(..12)
+                .context("Truncated PROXY protocol v2 header")?;
+            let src = Ipv4Addr::from(<[u8; 4]>::try_from(&payload[0..4])?);
+            let dst = Ipv4Addr::from(<[u8; 4]>::try_from(&payload[4..8])?);
+            Addresses {
+                source: SocketAddr::new(src.into(), port(&payload[8..10])),
+                destination: SocketAddr::new(dst.into(), port(&payload[10..12])),
+            }
+        }
+        0x21 => {
+            let payload = payload
+                .get(..36)
+                .context("Truncated PROXY protocol v2 header")?;
+            let src = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[0..16])?);
+            let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[16..32])?);
+            Addresses {
+                source: SocketAddr::new(src.into(), port(&payload[32..34])),
+                destination: SocketAddr::new(dst.into(), port(&payload[34..36])),
+            }
+        }
+        // UNSPEC, UDP or unix sockets carry no usable client address
+        _ => return Ok(None),
+    };
+    Ok(Some(addresses))
+}
+
+fn port(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+impl Addresses {
+    /// both addresses as IPv4 if possible, otherwise both as IPv6
+    fn same_family(&self) -> (SocketAddr, SocketAddr) {
+        let source = SocketAddr::new(self.source.ip().to_canonical(), self.source.port());
+        let destination = SocketAddr::new(
+            self.destination.ip().to_canonical(),
+            self.destination.port(),
+        );
+        if source.is_ipv4() == destination.is_ipv4() {
+            return (source, destination);
+        }
+        (
+            SocketAddr::new(to_ipv6(source.ip()).into(), source.port()),
+            SocketAddr::new(to_ipv6(destination.ip()).into(), destination.port()),
+        )
+    }
+}
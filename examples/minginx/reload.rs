@@ -0,0 +1,76 @@
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Result};
+use tokio::{
+    fs,
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    task,
+};
+use tracing::{error, info};
+
+use crate::config::Config;
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// swap the shared config on SIGHUP or when the config file changes,
+/// connections already accepted keep the config they started with
+#[derive(Debug)]
+pub struct Reloader {
+    path: PathBuf,
+    sender: watch::Sender<Arc<Config>>,
+}
+
+impl Reloader {
+    pub fn new(path: impl Into<PathBuf>, sender: watch::Sender<Arc<Config>>) -> Self {
+        Self {
+            path: path.into(),
+            sender,
+        }
+    }
+
+    pub async fn reload(&self) -> Result<()> {
+        // the config and the TLS files it refers to are read with blocking io
+        let path = self.path.clone();
+        let config = task::spawn_blocking(move || Config::load(path)).await??;
+        if !self.sender.borrow().same_listeners(&config) {
+            bail!("Listeners can not be changed by reload, restart instead");
+        }
+        self.sender.send_replace(Arc::new(config));
+        info!("Config reloaded from: {}", self.path.display());
+        Ok(())
+    }
+
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        let mut modified = self.modified().await;
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => info!("Received SIGHUP, reloading config"),
+                _ = interval.tick() => {
+                    let current = self.modified().await;
+                    if current == modified {
+                        continue;
+                    }
+                    modified = current;
+                    info!("Config file changed, reloading config");
+                }
+            }
+            if let Err(e) = self.reload().await {
+                error!("Invalid config, keep the current one: {:?}", e);
+            }
+        }
+    }
+
+    async fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path)
+            .await
+            .and_then(|m| m.modified())
+            .ok()
+    }
+}
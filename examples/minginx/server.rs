@@ -0,0 +1,354 @@
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::watch,
+    task::JoinSet,
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{field::display, info, warn, Instrument, Span};
+
+use crate::{
+    access_log::AccessLog,
+    config::{Config, ListenerConfig, ListenerMode, MaxConnections, WhenFull},
+    http,
+    limits::{Connections, IpSlot, Slot},
+    proxy::{self, CloseReason},
+    proxy_protocol::{self, Addresses},
+    state::{ActiveConnection, State, Tracked},
+    telemetry,
+    tls::MaybeTlsStream,
+    udp,
+};
+
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// the listeners of a config, bound and ready to serve
+#[derive(Debug)]
+pub struct Server {
+    config: watch::Receiver<Arc<Config>>,
+    state: Arc<State>,
+    listeners: Vec<(String, Listener)>,
+}
+
+#[derive(Debug)]
+enum Listener {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+impl Server {
+    pub async fn bind(config: watch::Receiver<Arc<Config>>, state: Arc<State>) -> Result<Self> {
+        let mut listeners = Vec::new();
+        for listener_config in &config.borrow().clone().listeners {
+            let listener = if listener_config.is_udp() {
+                Listener::Udp(UdpSocket::bind(&listener_config.listen_addr).await?)
+            } else {
+                Listener::Tcp(TcpListener::bind(&listener_config.listen_addr).await?)
+            };
+            info!(
+                "{:?} listener {} listen_addr: {}",
+                listener_config.mode, listener_config.name, listener_config.listen_addr
+            );
+            listeners.push((listener_config.name.clone(), listener));
+        }
+        Ok(Self {
+            config,
+            state,
+            listeners,
+        })
+    }
+
+    /// the bound address, tests ask for port 0
+    #[cfg(test)]
+    pub fn local_addr(&self, name: &str) -> Option<SocketAddr> {
+        let (_, listener) = self.listeners.iter().find(|(n, _)| n == name)?;
+        match listener {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            Listener::Udp(socket) => socket.local_addr().ok(),
+        }
+    }
+
+    /// serve all listeners until one fails or all stop on shutdown
+    pub async fn run(self) -> Result<()> {
+        let mut listeners = JoinSet::new();
+        listeners.spawn(follow_bandwidth(self.config.clone(), self.state.clone()));
+        for (name, listener) in self.listeners {
+            let (config, state) = (self.config.clone(), self.state.clone());
+            match listener {
+                Listener::Tcp(listener) => listeners.spawn(serve(listener, config, state, name)),
+                Listener::Udp(socket) => listeners.spawn(udp::serve(socket, config, state, name)),
+            };
+        }
+        while let Some(res) = listeners.join_next().await {
+            res??;
+        }
+        Ok(())
+    }
+}
+
+/// apply reloaded bandwidth limits to the connections already open
+async fn follow_bandwidth(
+    mut config: watch::Receiver<Arc<Config>>,
+    state: Arc<State>,
+) -> Result<()> {
+    loop {
+        tokio::select! {
+            res = config.changed() => {
+                if res.is_err() {
+                    return Ok(());
+                }
+            }
+            _ = state.shutdown.cancelled() => return Ok(()),
+        }
+        let config = config.borrow_and_update().clone();
+        state.bandwidth.configure(&config);
+    }
+}
+
+async fn serve(
+    listener: TcpListener,
+    config: watch::Receiver<Arc<Config>>,
+    state: Arc<State>,
+    name: String,
+) -> Result<()> {
+    let connections = &state.connections;
+    loop {
+        let accept = async {
+            let max_connections = config.borrow().max_connections;
+            if let Some(MaxConnections {
+                limit,
+                when_full: WhenFull::Wait,
+            }) = max_connections
+            {
+                connections.wait_below(Some(limit)).await;
+            }
+            listener.accept().await
+        };
+        let (stream, addr) = tokio::select! {
+            res = accept => res?,
+            _ = state.shutdown.cancelled() => {
+                info!("Listener {} stopped accepting", name);
+                return Ok(());
+            }
+        };
+        info!("Accepted connection from: {}", addr);
+        // registered before anything is awaited so a drain waits for it
+        let connection = state.active.register(addr, &name);
+        // the connection keeps using this config even if it is reloaded meanwhile
+        let config = config.borrow().clone();
+        let Some(slot) = acquire_slot(connections, &config, addr, &state.shutdown).await else {
+            continue;
+        };
+        let state = state.clone();
+        let name = name.clone();
+        let span = telemetry::connection_span(addr, &name);
+        tokio::spawn(
+            async move {
+                let _slot = slot;
+                handle_connection(config, state, &name, stream, &connection).await;
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// a connection past the PROXY protocol header, the admission checks and the TLS handshake
+struct Accepted {
+    stream: MaybeTlsStream,
+    client: Addresses,
+    mode: ListenerMode,
+    _ip_slot: IpSlot,
+}
+
+async fn handle_connection(
+    config: Arc<Config>,
+    state: Arc<State>,
+    name: &str,
+    stream: TcpStream,
+    connection: &Tracked,
+) {
+    let access_log = AccessLog::new(name);
+    let accepted = match accept(&config, &state, name, stream, connection).await {
+        Ok(accepted) => accepted,
+        Err(close_reason) => {
+            if let CloseReason::Error(e) = &close_reason {
+                warn!(
+                    "Error handling connection from {}: {:?}",
+                    connection.client(),
+                    e
+                );
+            }
+            telemetry::record_close(connection, Some(&close_reason));
+            access_log.finish(connection, &close_reason);
+            return;
+        }
+    };
+    let Accepted {
+        stream,
+        client,
+        mode,
+        _ip_slot,
+    } = accepted;
+    match mode {
+        ListenerMode::Tcp => {
+            proxy::handle_tcp(config, &state, connection, stream, client, access_log).await
+        }
+        // http connections get one record per request instead
+        ListenerMode::Http => {
+            let connection = connection.connection().clone();
+            http::handle_http(config, state, connection, stream).await
+        }
+        ListenerMode::Udp => unreachable!("udp listeners do not accept tcp connections"),
+    }
+}
+
+async fn accept(
+    config: &Config,
+    state: &State,
+    name: &str,
+    mut stream: TcpStream,
+    connection: &Tracked,
+) -> Result<Accepted, CloseReason> {
+    let listener_config = config
+        .listener(name)
+        .with_context(|| format!("Listener {} is not configured", name))
+        .map_err(CloseReason::Error)?;
+    let addr = connection.client();
+    let mut client = Addresses {
+        source: addr,
+        destination: stream
+            .local_addr()
+            .map_err(|e| CloseReason::Error(e.into()))?,
+    };
+    if listener_config.accept_proxy_protocol {
+        if !listener_config.trusts_proxy_protocol_from(addr.ip()) {
+            warn!(
+                "Reject connection from {}: not a trusted PROXY protocol peer",
+                addr
+            );
+            return Err(CloseReason::Rejected(anyhow!(
+                "not a trusted PROXY protocol peer"
+            )));
+        }
+        let header = until_closed(connection, async {
+            timeout(
+                PROXY_HEADER_TIMEOUT,
+                proxy_protocol::read_header(&mut stream),
+            )
+            .await
+            .context("Timed out reading PROXY protocol header")?
+        })
+        .await?;
+        if let Some(addresses) = header {
+            info!(
+                "Connection from {} is proxied for {}",
+                addr, addresses.source
+            );
+            Span::current().record("client", display(addresses.source));
+            connection.set_client(addresses.source);
+            client = addresses;
+        }
+    }
+    let ip_slot = match admit(&state.connections, listener_config, client.source.ip()) {
+        Ok(ip_slot) => ip_slot,
+        Err(e) => {
+            warn!("Reject connection from {}: {}", client.source, e);
+            return Err(CloseReason::Rejected(e));
+        }
+    };
+    let stream = match &listener_config.tls_config {
+        Some(tls_config) => {
+            let stream = until_closed(connection, async {
+                TlsAcceptor::from(tls_config.clone())
+                    .accept(stream)
+                    .await
+                    .context("TLS handshake failed")
+            })
+            .await?;
+            MaybeTlsStream::Tls(Box::new(stream.into()))
+        }
+        None => MaybeTlsStream::Plain(stream),
+    };
+    Ok(Accepted {
+        stream,
+        client,
+        mode: listener_config.mode,
+        _ip_slot: ip_slot,
+    })
+}
+
+/// a step before the connection is proxied, given up if the connection is force closed meanwhile
+async fn until_closed<T>(
+    connection: &ActiveConnection,
+    step: impl Future<Output = Result<T>>,
+) -> Result<T, CloseReason> {
+    tokio::select! {
+        res = step => res.map_err(CloseReason::Error),
+        reason = connection.closed() => Err(reason.into()),
+    }
+}
+
+/// check the client against the listener ACL and the per client limit,
+/// the returned slot has to be held for as long as the connection is open
+fn admit(
+    connections: &Arc<Connections>,
+    listener_config: &ListenerConfig,
+    ip: IpAddr,
+) -> Result<IpSlot> {
+    if !listener_config.allows(ip) {
+        bail!("denied by acl");
+    }
+    connections
+        .try_acquire_ip(
+            &listener_config.name,
+            ip,
+            listener_config.max_connections_per_ip,
+        )
+        .context("too many connections from the client")
+}
+
+/// take a slot in the global connection limit, waiting for it or rejecting the connection,
+/// a wait is given up on shutdown
+async fn acquire_slot(
+    connections: &Arc<Connections>,
+    config: &Config,
+    addr: SocketAddr,
+    shutdown: &CancellationToken,
+) -> Option<Slot> {
+    let slot = match config.max_connections {
+        Some(MaxConnections {
+            limit,
+            when_full: WhenFull::Reject,
+        }) => match connections.try_acquire(Some(limit)) {
+            Some(slot) => slot,
+            None => {
+                warn!(
+                    "Reject connection from {}: too many connections ({})",
+                    addr,
+                    connections.total()
+                );
+                return None;
+            }
+        },
+        Some(MaxConnections { limit, .. }) => {
+            tokio::select! {
+                slot = connections.acquire(Some(limit)) => slot,
+                _ = shutdown.cancelled() => {
+                    info!("Close connection from {}: shutting down", addr);
+                    return None;
+                }
+            }
+        }
+        None => connections.acquire(None).await,
+    };
+    Some(slot)
+}
@@ -0,0 +1,142 @@
+use anyhow::{bail, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// enough for any ClientHello, even split over several records
+const MAX_CLIENT_HELLO_SIZE: usize = 64 * 1024;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// the bytes read from the client, to be replayed to the upstream, and the SNI in them
+#[derive(Debug)]
+pub struct ClientHello {
+    pub bytes: Vec<u8>,
+    pub server_name: Option<String>,
+}
+
+enum Parsed {
+    Incomplete,
+    Done(Option<String>),
+}
+
+/// read until the ClientHello is complete, a client that does not speak TLS gets no server name
+pub async fn read_client_hello<S>(stream: &mut S) -> Result<ClientHello>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut bytes = Vec::new();
+    loop {
+        if let Parsed::Done(server_name) = parse(&bytes) {
+            return Ok(ClientHello { bytes, server_name });
+        }
+        if bytes.len() >= MAX_CLIENT_HELLO_SIZE {
+            bail!("ClientHello larger than {} bytes", MAX_CLIENT_HELLO_SIZE);
+        }
+        // reading past the ClientHello does no harm, everything read is replayed
+        let mut buf = [0; 4096];
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(ClientHello {
+                bytes,
+                server_name: None,
+            });
+        }
+        bytes.extend_from_slice(&buf[..n]);
+    }
+}
+
+/// collect the handshake message from the TLS records and look for the SNI extension
+fn parse(buf: &[u8]) -> Parsed {
+    let mut handshake = Vec::new();
+    let mut records = Reader(buf);
+    loop {
+        let Some(content_type) = records.u8() else {
+            return Parsed::Incomplete;
+        };
+        if content_type != CONTENT_TYPE_HANDSHAKE {
+            return Parsed::Done(None);
+        }
+        let Some(fragment) = records.u16().and_then(|_| records.vec16()) else {
+            return Parsed::Incomplete;
+        };
+        handshake.extend_from_slice(fragment);
+        let mut message = Reader(&handshake);
+        match (message.u8(), message.u24()) {
+            (Some(HANDSHAKE_CLIENT_HELLO), Some(len)) => match message.take(len) {
+                Some(body) => return Parsed::Done(server_name(body)),
+                None => continue,
+            },
+            // the header of the handshake message is split over records as well
+            (Some(HANDSHAKE_CLIENT_HELLO), None) => continue,
+            (Some(_), _) => return Parsed::Done(None),
+            _ => continue,
+        }
+    }
+}
+
+fn server_name(client_hello: &[u8]) -> Option<String> {
+    let mut body = Reader(client_hello);
+    // version and random
+    body.take(2 + 32)?;
+    // session id, cipher suites and compression methods
+    body.vec8()?;
+    body.vec16()?;
+    body.vec8()?;
+    let mut extensions = Reader(body.vec16()?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = Reader(Reader(data).vec16()?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec16()?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                let name = std::str::from_utf8(name).ok()?;
+                return Some(name.to_ascii_lowercase());
+            }
+        }
+    }
+    None
+}
+
+/// big endian cursor, `None` once the input runs out
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+};
+
+use tokio::{io::Interest, net::TcpStream};
+
+/// a larger pipe moves more bytes per syscall, the kernel caps it at `fs.pipe-max-size`
+const PIPE_SIZE: usize = 1024 * 1024;
+
+const DEFAULT_PIPE_SIZE: usize = 64 * 1024;
+
+/// kernel buffer the bytes pass through between the two sockets
+#[derive(Debug)]
+struct Pipe {
+    reader: OwnedFd,
+    writer: OwnedFd,
+    size: usize,
+}
+
+/// move bytes from `reader` to `writer` with splice(2) until EOF without copying them
+/// to userspace, then shut down the write side of `writer`;
+/// `on_copy` is called with the number of bytes each time some are written
+pub async fn copy(
+    reader: &TcpStream,
+    writer: &TcpStream,
+    mut on_copy: impl FnMut(usize),
+) -> io::Result<()> {
+    let pipe = Pipe::new()?;
+    loop {
+        let n = reader
+            .async_io(Interest::READABLE, || {
+                splice(reader.as_raw_fd(), pipe.writer.as_raw_fd(), pipe.size)
+            })
+            .await?;
+        if n == 0 {
+            return shutdown_write(writer);
+        }
+        let mut remaining = n;
+        while remaining > 0 {
+            let written = writer
+                .async_io(Interest::WRITABLE, || {
+                    splice(pipe.reader.as_raw_fd(), writer.as_raw_fd(), remaining)
+                })
+                .await?;
+            remaining -= written;
+            on_copy(written);
+        }
+    }
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors pipe2 writes
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: both descriptors were just created and are owned by nothing else
+        let (reader, writer) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        // SAFETY: plain fcntl on a descriptor we own, failure leaves the default size
+        let size = unsafe {
+            libc::fcntl(
+                writer.as_raw_fd(),
+                libc::F_SETPIPE_SZ,
+                PIPE_SIZE as libc::c_int,
+            )
+        };
+        let size = if size > 0 {
+            size as usize
+        } else {
+            DEFAULT_PIPE_SIZE
+        };
+        Ok(Self {
+            reader,
+            writer,
+            size,
+        })
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    // SAFETY: both descriptors are open for the duration of the call, null offsets mean
+    // the current file position which is required for sockets and pipes
+    let n = unsafe {
+        libc::splice(
+            from,
+            ptr::null_mut(),
+            to,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+fn shutdown_write(stream: &TcpStream) -> io::Result<()> {
+    // SAFETY: the descriptor is owned by `stream` which outlives the call
+    if unsafe { libc::shutdown(stream.as_raw_fd(), libc::SHUT_WR) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use std::{
+    net::SocketAddr,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::Serialize;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::{health::Servers, limits::Connections, proxy::Traffic, throttle::Bandwidth};
+
+/// runtime state shared by all listeners and the admin api, it outlives config reloads
+#[derive(Debug, Default)]
+pub struct State {
+    pub connections: Arc<Connections>,
+    pub active: Arc<ActiveConnections>,
+    pub servers: Servers,
+    pub bandwidth: Arc<Bandwidth>,
+    /// cancelled on SIGTERM, listeners stop accepting and open connections are drained
+    pub shutdown: CancellationToken,
+}
+
+/// the connections and udp sessions currently open
+#[derive(Debug, Default)]
+pub struct ActiveConnections {
+    next_id: AtomicU64,
+    connections: DashMap<u64, Arc<ActiveConnection>>,
+    released: Notify,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ActiveConnection {
+    pub id: u64,
+    /// the peer until a PROXY protocol header names the client behind it
+    client: Mutex<SocketAddr>,
+    pub listener: String,
+    /// the server of the latest request for http connections
+    upstream: Mutex<Option<String>>,
+    pub started_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub traffic: Traffic,
+    #[serde(skip)]
+    closed: CancellationToken,
+    #[serde(skip)]
+    close_reason: OnceLock<ForceClose>,
+}
+
+/// why minginx closes a connection before it is done
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForceClose {
+    UpstreamDisabled,
+    /// still open when the drain deadline passed
+    Shutdown,
+}
+
+/// keeps a connection in the registry until dropped
+#[derive(Debug)]
+pub struct Tracked {
+    connections: Arc<ActiveConnections>,
+    connection: Arc<ActiveConnection>,
+}
+
+impl ActiveConnections {
+    /// called as soon as a connection is accepted, so a drain waits for it from the start
+    pub fn register(self: &Arc<Self>, client: SocketAddr, listener: &str) -> Tracked {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let connection = Arc::new(ActiveConnection {
+            id,
+            client: Mutex::new(client),
+            listener: listener.to_string(),
+            upstream: Mutex::new(None),
+            started_at: Utc::now(),
+            traffic: Traffic::default(),
+            closed: CancellationToken::new(),
+            close_reason: OnceLock::new(),
+        });
+        self.connections.insert(id, connection.clone());
+        Tracked {
+            connections: self.clone(),
+            connection,
+        }
+    }
+
+    /// oldest first
+    pub fn list(&self) -> Vec<Arc<ActiveConnection>> {
+        let mut connections: Vec<_> = self
+            .connections
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect();
+        connections.sort_by_key(|connection| connection.id);
+        connections
+    }
+
+    pub fn count_upstream(&self, server: &str) -> usize {
+        self.connections
+            .iter()
+            .filter(|entry| entry.is_upstream(server))
+            .count()
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// close the connections to `server`, return how many there were
+    pub fn close_upstream(&self, server: &str) -> usize {
+        self.close_where(
+            |connection| connection.is_upstream(server),
+            ForceClose::UpstreamDisabled,
+        )
+    }
+
+    /// close every connection, return how many there were
+    pub fn close_all(&self, reason: ForceClose) -> usize {
+        self.close_where(|_| true, reason)
+    }
+
+    fn close_where(&self, filter: impl Fn(&ActiveConnection) -> bool, reason: ForceClose) -> usize {
+        let mut closed = 0;
+        for entry in self.connections.iter() {
+            if filter(entry.value()) {
+                entry.close(reason);
+                closed += 1;
+            }
+        }
+        closed
+    }
+
+    /// wait until all connections are closed
+    pub async fn wait_empty(&self) {
+        loop {
+            // created before the check so a release in between is not missed
+            let released = self.released.notified();
+            if self.is_empty() {
+                return;
+            }
+            released.await;
+        }
+    }
+}
+
+impl ActiveConnection {
+    pub fn client(&self) -> SocketAddr {
+        *self.client.lock().unwrap()
+    }
+
+    pub fn set_client(&self, client: SocketAddr) {
+        *self.client.lock().unwrap() = client;
+    }
+
+    pub fn set_upstream(&self, server: &str) {
+        *self.upstream.lock().unwrap() = Some(server.to_string());
+    }
+
+    pub fn upstream(&self) -> Option<String> {
+        self.upstream.lock().unwrap().clone()
+    }
+
+    fn is_upstream(&self, server: &str) -> bool {
+        self.upstream.lock().unwrap().as_deref() == Some(server)
+    }
+
+    /// the first reason wins if the connection is closed twice
+    pub fn close(&self, reason: ForceClose) {
+        let _ = self.close_reason.set(reason);
+        self.closed.cancel();
+    }
+
+    /// resolve once the connection has to be closed
+    pub async fn closed(&self) -> ForceClose {
+        self.closed.cancelled().await;
+        self.close_reason
+            .get()
+            .copied()
+            .unwrap_or(ForceClose::Shutdown)
+    }
+}
+
+impl Tracked {
+    pub fn connection(&self) -> &Arc<ActiveConnection> {
+        &self.connection
+    }
+}
+
+impl Deref for Tracked {
+    type Target = ActiveConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.connection
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.connections.connections.remove(&self.connection.id);
+        self.connections.released.notify_waiters();
+    }
+}
@@ -0,0 +1,70 @@
+use std::{net::SocketAddr, sync::atomic::Ordering};
+
+use anyhow::Result;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    runtime::Tokio,
+    trace::{Config, Tracer},
+    Resource,
+};
+use opentelemetry_semantic_conventions::resource::SERVICE_NAME;
+use tracing::{field::Empty, info_span, Span};
+
+use crate::{config::OtlpConfig, proxy::CloseReason, state::ActiveConnection};
+
+/// export the spans to an OTLP collector over gRPC
+pub fn init_tracer(config: &OtlpConfig) -> Result<Tracer> {
+    let resource = Resource::new([KeyValue::new(SERVICE_NAME, config.service_name.clone())]);
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(&config.endpoint),
+        )
+        .with_trace_config(Config::default().with_resource(resource))
+        .install_batch(Tokio)?;
+    Ok(tracer)
+}
+
+/// one span per accepted connection or udp session, the rest is recorded at close
+pub fn connection_span(client: SocketAddr, listener: &str) -> Span {
+    info_span!(
+        "proxy_connection",
+        %client,
+        listener,
+        server_name = Empty,
+        upstream = Empty,
+        client_to_upstream_bytes = Empty,
+        upstream_to_client_bytes = Empty,
+        close_reason = Empty,
+        otel.status_code = Empty,
+        otel.status_message = Empty,
+    )
+}
+
+/// record the upstream and byte counts on the current connection span
+pub fn record_close(connection: &ActiveConnection, close_reason: Option<&CloseReason>) {
+    let span = Span::current();
+    if let Some(upstream) = connection.upstream() {
+        span.record("upstream", upstream);
+    }
+    // u64 is exported as a string, the counts fit into an i64 attribute
+    let traffic = &connection.traffic;
+    span.record(
+        "client_to_upstream_bytes",
+        traffic.client_to_upstream.load(Ordering::Relaxed) as i64,
+    );
+    span.record(
+        "upstream_to_client_bytes",
+        traffic.upstream_to_client.load(Ordering::Relaxed) as i64,
+    );
+    if let Some(close_reason) = close_reason {
+        span.record("close_reason", close_reason.to_string());
+        if let Some(error) = close_reason.error() {
+            span.record("otel.status_code", "ERROR");
+            span.record("otel.status_message", error);
+        }
+    }
+}
@@ -0,0 +1,1337 @@
+use std::{
+    collections::HashSet,
+    env, fs,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{ensure, Result};
+use opentelemetry::{
+    trace::{Status, TracerProvider as _},
+    Value,
+};
+use opentelemetry_sdk::{
+    export::trace::SpanData, testing::trace::InMemorySpanExporter, trace::TracerProvider,
+};
+use rustls::{pki_types::ServerName, ClientConnection};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream, UdpSocket},
+    sync::watch,
+    time::{sleep, timeout, Instant},
+};
+use tokio_rustls::TlsConnector;
+use tracing_subscriber::layer::SubscriberExt;
+
+use crate::{
+    access_log, admin,
+    config::{AccessLogConfig, Config, UpstreamTls},
+    health::ServerStatus,
+    limits::Connections,
+    proxy_protocol::{self, Addresses, Version, V2_SIGNATURE},
+    reload::Reloader,
+    server::Server,
+    sni,
+    state::State,
+    tls,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// listener settings to read PROXY protocol headers from the tests on loopback
+const TRUSTED_PROXY_PROTOCOL: &str =
+    "    accept_proxy_protocol: true\n    proxy_protocol_from: [127.0.0.0/8]";
+
+const MIB: u64 = 1024 * 1024;
+
+/// bytes per round of `splice_throughput` unless `MINGINX_BENCH_MIB` says otherwise
+const BENCH_SIZE_MIB: u64 = 1024;
+
+const BENCH_ROUNDS: usize = 3;
+
+/// echo everything back, shut down the write side on EOF and count the connections
+async fn start_echo() -> Result<(SocketAddr, Arc<AtomicUsize>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            counter.fetch_add(1, Ordering::Relaxed);
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await?;
+                writer.shutdown().await?;
+                Ok::<_, anyhow::Error>(())
+            });
+        }
+    });
+    Ok((addr, accepted))
+}
+
+/// read until EOF, only then reply with the number of bytes received
+async fn start_counter() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut received = Vec::new();
+                stream.read_to_end(&mut received).await?;
+                let reply = format!("got {} bytes", received.len());
+                stream.write_all(reply.as_bytes()).await?;
+                Ok::<_, anyhow::Error>(())
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// read everything, then reply with the number of bytes received
+async fn start_sink() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let received = tokio::io::copy(&mut reader, &mut tokio::io::sink()).await?;
+                writer.write_u64(received).await?;
+                Ok::<_, anyhow::Error>(())
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// reply to every datagram with the address it came from and its content
+async fn start_udp_echo() -> Result<SocketAddr> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let addr = socket.local_addr()?;
+    tokio::spawn(async move {
+        let mut buf = vec![0; 64 * 1024];
+        while let Ok((n, peer)) = socket.recv_from(&mut buf).await {
+            let reply = [peer.to_string().as_bytes(), b" ", &buf[..n]].concat();
+            if socket.send_to(&reply, peer).await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(addr)
+}
+
+/// send `datagram` to the connected proxy, the upstream socket that echoed it and the echo
+async fn udp_round_trip(client: &UdpSocket, datagram: &str) -> Result<(String, String)> {
+    client.send(datagram.as_bytes()).await?;
+    let mut buf = vec![0; 64 * 1024];
+    let n = timeout(TIMEOUT, client.recv(&mut buf)).await??;
+    let reply = String::from_utf8(buf[..n].to_vec())?;
+    let (peer, echo) = reply.split_once(' ').unwrap_or_default();
+    Ok((peer.to_string(), echo.to_string()))
+}
+
+/// answer every request with `body` and close the connection
+async fn start_http(body: &'static str) -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut req = Vec::new();
+                while !req.ends_with(b"\r\n\r\n") {
+                    req.push(stream.read_u8().await?);
+                }
+                let res = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(res.as_bytes()).await?;
+                Ok::<_, anyhow::Error>(())
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// an address nobody listens on, the port stays reserved while the socket is held
+fn closed_addr() -> Result<(SocketAddr, TcpSocket)> {
+    let socket = TcpSocket::new_v4()?;
+    socket.bind("127.0.0.1:0".parse()?)?;
+    // bound but not listening, connections to it are refused
+    Ok((socket.local_addr()?, socket))
+}
+
+/// start minginx with a `test` listener on an ephemeral port in front of `servers`
+async fn start_proxy(
+    mode: &str,
+    servers: &[SocketAddr],
+    extra: &str,
+) -> Result<(SocketAddr, Arc<State>)> {
+    serve(test_config(mode, servers, extra)?).await
+}
+
+/// start minginx with a config that has a `test` listener
+async fn serve(config: Config) -> Result<(SocketAddr, Arc<State>)> {
+    let (addr, state, _) = serve_reloadable(config).await?;
+    Ok((addr, state))
+}
+
+/// like `serve`, reloaded configs are sent through the returned sender
+async fn serve_reloadable(
+    config: Config,
+) -> Result<(SocketAddr, Arc<State>, watch::Sender<Arc<Config>>)> {
+    let (sender, receiver) = watch::channel(Arc::new(config));
+    let state = Arc::new(State::default());
+    let server = Server::bind(receiver, state.clone()).await?;
+    let addr = server.local_addr("test").expect("test listener is bound");
+    tokio::spawn(server.run());
+    Ok((addr, state, sender))
+}
+
+/// a config with a `test` listener, `extra` is appended to its settings
+fn test_config(mode: &str, servers: &[SocketAddr], extra: &str) -> Result<Config> {
+    Config::parse(&test_yaml(mode, servers, extra))
+}
+
+fn test_yaml(mode: &str, servers: &[SocketAddr], extra: &str) -> String {
+    let servers: Vec<String> = servers.iter().map(|s| format!("\"{}\"", s)).collect();
+    format!(
+        r#"
+listeners:
+  - name: test
+    listen_addr: 127.0.0.1:0
+    mode: {mode}
+    upstream: test
+    routes:
+      - upstream: test
+{extra}
+upstreams:
+  test:
+    servers: [{servers}]
+"#,
+        mode = mode,
+        extra = extra,
+        servers = servers.join(", ")
+    )
+}
+
+/// start the admin api in front of `config`, a reload reads `path`
+async fn start_admin(
+    config: Config,
+    path: &Path,
+) -> Result<(SocketAddr, Arc<State>, watch::Receiver<Arc<Config>>)> {
+    let (sender, receiver) = watch::channel(Arc::new(config));
+    let state = Arc::new(State::default());
+    let reloader = Arc::new(Reloader::new(path, sender));
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(admin::serve(
+        listener,
+        receiver.clone(),
+        state.clone(),
+        reloader,
+    ));
+    Ok((addr, state, receiver))
+}
+
+/// send a request without body, the status and the body of the response
+async fn admin_request(admin: SocketAddr, method: &str, path: &str) -> Result<(u16, String)> {
+    let mut stream = TcpStream::connect(admin).await?;
+    let req = format!(
+        "{} {} HTTP/1.1\r\nHost: admin\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        method, path
+    );
+    stream.write_all(req.as_bytes()).await?;
+    let mut res = String::new();
+    timeout(TIMEOUT, stream.read_to_string(&mut res)).await??;
+    let status = res.get(9..12).unwrap_or_default().parse()?;
+    let (_, body) = res.split_once("\r\n\r\n").unwrap_or_default();
+    Ok((status, body.to_string()))
+}
+
+/// wait until `count` connections are registered
+async fn wait_active(state: &State, count: usize) -> Result<()> {
+    timeout(TIMEOUT, async {
+        while state.active.len() != count {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+/// send `payload`, close the write side and read the reply until EOF
+async fn round_trip(proxy: SocketAddr, payload: &[u8]) -> Result<Vec<u8>> {
+    let stream = TcpStream::connect(proxy).await?;
+    let (mut reader, mut writer) = stream.into_split();
+    let payload = payload.to_vec();
+    // write and read at the same time, the echo would stall on full buffers otherwise
+    let write = tokio::spawn(async move {
+        writer.write_all(&payload).await?;
+        writer.shutdown().await?;
+        Ok::<_, std::io::Error>(writer)
+    });
+    let mut received = Vec::new();
+    timeout(TIMEOUT, reader.read_to_end(&mut received)).await??;
+    write.await??;
+    Ok(received)
+}
+
+/// the json records of all access log files in `directory`
+fn access_records(directory: &Path) -> Result<Vec<serde_json::Value>> {
+    let mut records = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        for line in fs::read_to_string(entry?.path())?.lines() {
+            records.push(serde_json::from_str(line)?);
+        }
+    }
+    Ok(records)
+}
+
+/// wait for the span of a closed connection
+async fn finished_span(exporter: &InMemorySpanExporter) -> Result<SpanData> {
+    let span = timeout(TIMEOUT, async {
+        loop {
+            let spans = exporter.get_finished_spans()?;
+            if let Some(span) = spans.into_iter().find(|s| s.name == "proxy_connection") {
+                return Ok::<_, anyhow::Error>(span);
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await??;
+    Ok(span)
+}
+
+fn attribute(span: &SpanData, key: &str) -> Option<Value> {
+    span.attributes
+        .iter()
+        .find(|kv| kv.key.as_str() == key)
+        .map(|kv| kv.value.clone())
+}
+
+/// path of a file in the fixtures directory, the test certificates are signed by `ca.pem`
+fn fixture(name: &str) -> String {
+    format!(
+        "{}/examples/minginx/fixtures/{}",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    )
+}
+
+/// the first flight of a TLS client asking for `server_name`
+fn client_hello(server_name: &str) -> Result<Vec<u8>> {
+    let config = tls::client_config(None)?;
+    let mut client = ClientConnection::new(config, ServerName::try_from(server_name.to_string())?)?;
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello)?;
+    Ok(hello)
+}
+
+/// send `size` bytes to the sink behind `proxy`, the time until the sink confirmed them
+async fn send_to_sink(proxy: SocketAddr, size: u64) -> Result<Duration> {
+    let buf = vec![0xa5; MIB as usize];
+    let mut stream = TcpStream::connect(proxy).await?;
+    let start = Instant::now();
+    let mut sent = 0;
+    while sent < size {
+        let n = (size - sent).min(MIB) as usize;
+        stream.write_all(&buf[..n]).await?;
+        sent += n as u64;
+    }
+    stream.shutdown().await?;
+    let received = stream.read_u64().await?;
+    let elapsed = start.elapsed();
+    ensure!(
+        received == size,
+        "Sent {} bytes but the sink received {}",
+        size,
+        received
+    );
+    Ok(elapsed)
+}
+
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+#[test]
+fn http_requests_are_routed_by_longest_prefix_and_host() -> Result<()> {
+    let config = Config::parse(
+        r#"
+listeners:
+  - name: test
+    listen_addr: 127.0.0.1:0
+    mode: http
+    routes:
+      - upstream: root
+      - path_prefix: /api
+        upstream: api
+      - path_prefix: /api/v1/
+        upstream: api_v1
+      - host: admin.example.com
+        path_prefix: /admin
+        upstream: admin
+upstreams:
+  root: { servers: ["127.0.0.1:8001"] }
+  api: { servers: ["127.0.0.1:8002"] }
+  api_v1: { servers: ["127.0.0.1:8003"] }
+  admin: { servers: ["127.0.0.1:8004"] }
+"#,
+    )?;
+    let listener = config.listener("test").expect("test listener");
+    let cases = [
+        (None, "/", "root"),
+        (None, "/index.html", "root"),
+        (None, "/api", "api"),
+        (None, "/api/users", "api"),
+        (None, "/api/v1", "api_v1"),
+        (None, "/api/v1/users", "api_v1"),
+        (None, "/api/v10", "api"),
+        // a prefix only matches whole path segments
+        (None, "/apix", "root"),
+        (None, "/apix/users", "root"),
+        (Some("admin.example.com"), "/admin/users", "admin"),
+        (Some("Admin.Example.COM"), "/admin", "admin"),
+        (Some("www.example.com"), "/admin", "root"),
+        (None, "/admin", "root"),
+    ];
+    for (host, path, upstream) in cases {
+        let route = listener
+            .route(host, path)
+            .map(|route| route.upstream.as_str());
+        assert_eq!(route, Some(upstream), "{:?} {}", host, path);
+    }
+    Ok(())
+}
+
+#[test]
+fn matched_prefix_is_rewritten() -> Result<()> {
+    let config = Config::parse(
+        r#"
+listeners:
+  - name: test
+    listen_addr: 127.0.0.1:0
+    mode: http
+    routes:
+      - path_prefix: /static
+        rewrite: /
+        upstream: test
+      - path_prefix: /api/v1/
+        rewrite: /v1
+        upstream: test
+      - path_prefix: /legacy
+        upstream: test
+      - rewrite: /app/
+        upstream: test
+upstreams:
+  test: { servers: ["127.0.0.1:8001"] }
+"#,
+    )?;
+    let listener = config.listener("test").expect("test listener");
+    let cases = [
+        ("/static/app.js", "/app.js"),
+        ("/static/", "/"),
+        ("/static", "/"),
+        ("/api/v1/users", "/v1/users"),
+        ("/api/v1", "/v1"),
+        // no rewrite configured
+        ("/legacy/page", "/legacy/page"),
+        ("/", "/app/"),
+        ("/index.html", "/app/index.html"),
+    ];
+    for (path, rewritten) in cases {
+        let route = listener.route(None, path).expect("a route matches");
+        assert_eq!(route.rewrite_path(path), rewritten, "{}", path);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn proxy_protocol_headers_round_trip() -> Result<()> {
+    let pairs = [
+        ("203.0.113.7:40000", "192.0.2.1:443"),
+        ("[2001:db8::7]:40000", "[2001:db8::1]:443"),
+    ];
+    for (source, destination) in pairs {
+        let addresses = Addresses {
+            source: source.parse()?,
+            destination: destination.parse()?,
+        };
+        for version in [Version::V1, Version::V2] {
+            let mut bytes = proxy_protocol::encode(version, &addresses);
+            bytes.extend_from_slice(b"payload");
+            let mut reader = bytes.as_slice();
+            let header = proxy_protocol::read_header(&mut reader).await?;
+            assert_eq!(header, Some(addresses), "{:?} {}", version, source);
+            // the proxied stream starts right after the header
+            assert_eq!(reader, b"payload", "{:?} {}", version, source);
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn proxy_protocol_headers_without_client_address_are_passed() -> Result<()> {
+    let mut local = V2_SIGNATURE.to_vec();
+    local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+    let headers: [&[u8]; 2] = [b"PROXY UNKNOWN\r\n", &local];
+    for header in headers {
+        let mut reader = header;
+        assert_eq!(proxy_protocol::read_header(&mut reader).await?, None);
+        assert!(reader.is_empty());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn invalid_proxy_protocol_headers_are_rejected() -> Result<()> {
+    let addresses = Addresses {
+        source: "203.0.113.7:40000".parse()?,
+        destination: "192.0.2.1:443".parse()?,
+    };
+    let v2 = proxy_protocol::encode(Version::V2, &addresses);
+    let v2_with = |tail: &[u8]| [&V2_SIGNATURE[..], tail].concat();
+    let invalid: Vec<(&str, Vec<u8>)> = vec![
+        ("no header", b"GET / HTTP/1.1\r\n\r\n".to_vec()),
+        (
+            "bad v2 signature",
+            [b"\r\n\r\n\0\r\nQUIT!", &v2[12..]].concat(),
+        ),
+        (
+            "v1 without CRLF",
+            b"PROXY TCP4 203.0.113.7 192.0.2.1 40000 443".to_vec(),
+        ),
+        (
+            "v1 too long",
+            format!("PROXY TCP4 {}\r\n", "1".repeat(200)).into_bytes(),
+        ),
+        (
+            "v1 bad address",
+            b"PROXY TCP4 203.0.113.x 192.0.2.1 40000 443\r\n".to_vec(),
+        ),
+        (
+            "v1 bad family",
+            b"PROXY UDP4 203.0.113.7 192.0.2.1 40000 443\r\n".to_vec(),
+        ),
+        ("v2 truncated", v2[..v2.len() - 3].to_vec()),
+        (
+            "v2 short address block",
+            v2_with(&[0x21, 0x11, 0, 4, 203, 0, 113, 7]),
+        ),
+        ("v2 version 1", v2_with(&[0x11, 0x11, 0, 12])),
+        // 600 bytes of address block and TLVs, all of them sent
+        (
+            "v2 too long",
+            v2_with(&[&[0x21, 0x11, 0x02, 0x58][..], &[0; 600]].concat()),
+        ),
+    ];
+    for (case, bytes) in invalid {
+        let mut reader = bytes.as_slice();
+        let res = proxy_protocol::read_header(&mut reader).await;
+        assert!(res.is_err(), "{}: {:?}", case, res);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn proxy_protocol_headers_are_only_trusted_from_listed_peers() -> Result<()> {
+    let (echo, _) = start_echo().await?;
+    let addresses = Addresses {
+        source: "203.0.113.7:40000".parse()?,
+        destination: "192.0.2.1:443".parse()?,
+    };
+    let cases = [("127.0.0.0/8", true), ("192.0.2.0/24", false)];
+    for (trusted, passed) in cases {
+        let extra = format!(
+            "    accept_proxy_protocol: true\n    proxy_protocol_from: [{}]\n    allow: [203.0.113.0/24]",
+            trusted
+        );
+        let (proxy, _) = start_proxy("tcp", &[echo], &extra).await?;
+        let mut stream = TcpStream::connect(proxy).await?;
+        let mut header = proxy_protocol::encode(Version::V1, &addresses);
+        header.extend_from_slice(b"hello");
+        // writing to a connection that is already closed may fail
+        let _ = stream.write_all(&header).await;
+        let _ = stream.shutdown().await;
+        let mut buf = Vec::new();
+        let read = timeout(TIMEOUT, stream.read_to_end(&mut buf)).await?;
+        // the header names an allowed client, only a trusted peer gets through the ACL with it
+        if passed {
+            read?;
+            assert_eq!(buf, b"hello", "{}", trusted);
+        } else {
+            assert!(read.is_err() || buf.is_empty(), "{}: {:?}", trusted, buf);
+        }
+    }
+    let untrusted = test_config("tcp", &[echo], "    accept_proxy_protocol: true");
+    assert!(untrusted.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn large_payload_is_forwarded_byte_exact() -> Result<()> {
+    let (echo, _) = start_echo().await?;
+    let (proxy, _) = start_proxy("tcp", &[echo], "").await?;
+    let payload = payload(16 * 1024 * 1024 + 7);
+    let received = round_trip(proxy, &payload).await?;
+    assert!(received == payload, "echoed payload differs");
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn large_payload_is_forwarded_byte_exact_with_splice() -> Result<()> {
+    let (echo, _) = start_echo().await?;
+    let (proxy, _) = start_proxy("tcp", &[echo], "    splice: true").await?;
+    let payload = payload(16 * 1024 * 1024 + 7);
+    let received = round_trip(proxy, &payload).await?;
+    assert!(received == payload, "echoed payload differs");
+    Ok(())
+}
+
+/// compare the throughput with and without the splice(2) fast path, run it with
+/// `cargo test --release --example minginx -- --ignored --nocapture splice_throughput`
+#[cfg(target_os = "linux")]
+#[ignore]
+#[tokio::test(flavor = "multi_thread")]
+async fn splice_throughput() -> Result<()> {
+    let size = match env::var("MINGINX_BENCH_MIB") {
+        Ok(size) => size.parse::<u64>()? * MIB,
+        Err(_) => BENCH_SIZE_MIB * MIB,
+    };
+    let sink = start_sink().await?;
+    for (name, extra) in [("userspace", ""), ("splice", "    splice: true")] {
+        let (proxy, _) = start_proxy("tcp", &[sink], extra).await?;
+        for round in 1..=BENCH_ROUNDS {
+            let elapsed = send_to_sink(proxy, size).await?;
+            println!(
+                "{:<10} round {}: {} MiB in {:?}, {:.0} MiB/s",
+                name,
+                round,
+                size / MIB,
+                elapsed,
+                (size / MIB) as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn half_close_is_passed_on_to_the_upstream() -> Result<()> {
+    let counter = start_counter().await?;
+    for extra in ["", "    splice: true"] {
+        let (proxy, _) = start_proxy("tcp", &[counter], extra).await?;
+        // the counter only replies after it saw EOF, so the reply proves the half-close
+        // reached it while the way back stayed open
+        let received = round_trip(proxy, &payload(100_000)).await?;
+        assert_eq!(String::from_utf8(received)?, "got 100000 bytes");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn upstream_down_closes_tcp_connection() -> Result<()> {
+    let (down, _reserved) = closed_addr()?;
+    let (proxy, state) = start_proxy("tcp", &[down], "").await?;
+    let mut stream = TcpStream::connect(proxy).await?;
+    let mut buf = Vec::new();
+    // either a clean EOF or a reset, but never any data
+    let read = timeout(TIMEOUT, stream.read_to_end(&mut buf)).await?;
+    assert!(read.is_err() || buf.is_empty());
+    let health = state.servers.health(&down.to_string());
+    assert_eq!(health.consecutive_failures, 1);
+    assert!(health.last_error.is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn upstream_down_returns_bad_gateway() -> Result<()> {
+    let (down, _reserved) = closed_addr()?;
+    let (proxy, _) = start_proxy("http", &[down], "").await?;
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut res = String::new();
+    timeout(TIMEOUT, stream.read_to_string(&mut res)).await??;
+    assert!(
+        res.starts_with("HTTP/1.1 502"),
+        "unexpected response: {}",
+        res
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn connections_are_balanced_round_robin() -> Result<()> {
+    let mut servers = Vec::new();
+    let mut counters = Vec::new();
+    for _ in 0..3 {
+        let (addr, accepted) = start_echo().await?;
+        servers.push(addr);
+        counters.push(accepted);
+    }
+    let (proxy, state) = start_proxy("tcp", &servers, "").await?;
+    for _ in 0..30 {
+        assert_eq!(round_trip(proxy, b"ping").await?, b"ping");
+    }
+    let accepted: Vec<usize> = counters.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+    assert_eq!(accepted, [10, 10, 10]);
+
+    // a drained server gets no new connections
+    state
+        .servers
+        .set_status(&servers[0].to_string(), ServerStatus::Draining);
+    for _ in 0..10 {
+        assert_eq!(round_trip(proxy, b"ping").await?, b"ping");
+    }
+    let accepted: Vec<usize> = counters.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+    assert_eq!(accepted, [10, 15, 15]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn connections_per_ip_are_limited_per_listener() -> Result<()> {
+    let (echo, _) = start_echo().await?;
+    let extra = "    max_connections_per_ip: 1
+  - name: other
+    listen_addr: 127.0.0.1:0
+    mode: tcp
+    upstream: test
+    max_connections_per_ip: 1";
+    let (_config, receiver) = watch::channel(Arc::new(test_config("tcp", &[echo], extra)?));
+    let server = Server::bind(receiver, Arc::new(State::default())).await?;
+    let listeners =
+        ["test", "other"].map(|name| server.local_addr(name).expect("listener is bound"));
+    tokio::spawn(server.run());
+
+    // the connection on one listener does not count against the limit of the other
+    let mut open = Vec::new();
+    for proxy in listeners {
+        let mut stream = TcpStream::connect(proxy).await?;
+        stream.write_all(b"hello").await?;
+        let mut buf = [0; 5];
+        timeout(TIMEOUT, stream.read_exact(&mut buf)).await??;
+        open.push(stream);
+    }
+    let mut rejected = TcpStream::connect(listeners[0]).await?;
+    let mut buf = Vec::new();
+    let read = timeout(TIMEOUT, rejected.read_to_end(&mut buf)).await?;
+    assert!(read.is_err() || buf.is_empty());
+
+    // an IPv4-mapped address is the IPv4 client it stands for
+    let connections = Arc::new(Connections::default());
+    let mapped = connections.try_acquire_ip("test", "::ffff:192.0.2.1".parse()?, Some(1));
+    assert!(mapped.is_some());
+    assert!(connections
+        .try_acquire_ip("test", "192.0.2.1".parse()?, Some(1))
+        .is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn udp_sessions_are_kept_per_client_until_idle() -> Result<()> {
+    let echo = start_udp_echo().await?;
+    let (proxy, state) = start_proxy("udp", &[echo], "    idle_timeout: 1").await?;
+    let mut upstream_sockets = Vec::new();
+    for i in 0..2 {
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        client.connect(proxy).await?;
+        let mut peers = HashSet::new();
+        for n in 0..3 {
+            let datagram = format!("client {} datagram {}", i, n);
+            let (peer, echo) = udp_round_trip(&client, &datagram).await?;
+            assert_eq!(echo, datagram);
+            peers.insert(peer);
+        }
+        // the datagrams of a client all go out through the socket of its session
+        assert_eq!(peers.len(), 1, "{:?}", peers);
+        upstream_sockets.extend(peers);
+    }
+    assert_ne!(
+        upstream_sockets[0], upstream_sockets[1],
+        "clients share a session"
+    );
+    assert_eq!(state.active.len(), 2);
+
+    timeout(TIMEOUT, async {
+        while !state.active.is_empty() {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await?;
+
+    // a client coming back after its session expired gets a new one
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    client.connect(proxy).await?;
+    let (_, echo) = udp_round_trip(&client, "again").await?;
+    assert_eq!(echo, "again");
+    assert_eq!(state.active.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn servers_are_drained_and_enabled_through_the_admin_api() -> Result<()> {
+    let servers: Vec<SocketAddr> = (1..=3)
+        .map(|port| SocketAddr::from(([127, 0, 0, 1], 8000 + port)))
+        .collect();
+    let path = env::temp_dir().join("minginx-unused.yml");
+    let (admin, state, config) = start_admin(test_config("tcp", &servers, "")?, &path).await?;
+    let picked = || -> HashSet<String> {
+        let config = config.borrow();
+        let upstream = config.upstream("test").expect("test upstream");
+        (0..6)
+            .filter_map(|_| upstream.next_server(&state.servers).map(str::to_string))
+            .collect()
+    };
+    assert_eq!(picked().len(), 3);
+
+    let drained = servers[1].to_string();
+    let (status, body) =
+        admin_request(admin, "POST", &format!("/servers/{}/drain", drained)).await?;
+    assert_eq!(status, 200, "{}", body);
+    let view: serde_json::Value = serde_json::from_str(&body)?;
+    assert_eq!(view["status"], "draining");
+    assert_eq!(view["active_connections"], 0);
+    let picked_while_drained = picked();
+    assert_eq!(picked_while_drained.len(), 2);
+    assert!(!picked_while_drained.contains(&drained));
+
+    let (status, body) = admin_request(admin, "GET", "/upstreams").await?;
+    assert_eq!(status, 200);
+    let upstreams: serde_json::Value = serde_json::from_str(&body)?;
+    assert_eq!(upstreams["test"][1]["address"], drained.as_str());
+    assert_eq!(upstreams["test"][1]["status"], "draining");
+
+    let (status, _) = admin_request(admin, "POST", "/servers/192.0.2.1:80/drain").await?;
+    assert_eq!(status, 404);
+
+    let (status, _) = admin_request(admin, "POST", &format!("/servers/{}/enable", drained)).await?;
+    assert_eq!(status, 200);
+    assert!(picked().contains(&drained));
+    Ok(())
+}
+
+#[tokio::test]
+async fn config_is_reloaded_through_the_admin_api() -> Result<()> {
+    let one: SocketAddr = "127.0.0.1:8001".parse()?;
+    let two: SocketAddr = "127.0.0.1:8002".parse()?;
+    let path = env::temp_dir().join(format!("minginx-reload-{}.yml", std::process::id()));
+    fs::write(&path, test_yaml("tcp", &[one, two], ""))?;
+    let (admin, _, config) = start_admin(test_config("tcp", &[one], "")?, &path).await?;
+
+    let (status, body) = admin_request(admin, "POST", "/reload").await?;
+    assert_eq!(status, 204, "{}", body);
+    assert_eq!(
+        config.borrow().upstream("test").map(|u| u.servers.len()),
+        Some(2)
+    );
+    let (status, body) = admin_request(admin, "GET", "/config").await?;
+    assert_eq!(status, 200);
+    let reloaded: serde_json::Value = serde_json::from_str(&body)?;
+    assert_eq!(reloaded["upstreams"]["test"]["servers"][1], two.to_string());
+
+    // listeners are bound once, a reload can not move them
+    let moved = test_yaml("tcp", &[one], "").replace("127.0.0.1:0", "127.0.0.1:1");
+    fs::write(&path, moved)?;
+    let (status, body) = admin_request(admin, "POST", "/reload").await?;
+    fs::remove_file(&path)?;
+    assert_eq!(status, 422);
+    assert!(body.contains("Listeners can not be changed"), "{}", body);
+    assert_eq!(
+        config.borrow().upstream("test").map(|u| u.servers.len()),
+        Some(2)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn drain_waits_for_connections_still_reading_the_proxy_header() -> Result<()> {
+    let (echo, _) = start_echo().await?;
+    let (proxy, state) = start_proxy("tcp", &[echo], TRUSTED_PROXY_PROTOCOL).await?;
+    let mut stream = TcpStream::connect(proxy).await?;
+    wait_active(&state, 1).await?;
+    let drain = tokio::spawn({
+        let state = state.clone();
+        async move { crate::drain(&state, TIMEOUT).await }
+    });
+    sleep(Duration::from_millis(100)).await;
+    assert!(
+        !drain.is_finished(),
+        "drain did not wait for the connection"
+    );
+
+    // the transfer starts only after shutdown and still completes
+    let addresses = Addresses {
+        source: "203.0.113.7:40000".parse()?,
+        destination: proxy,
+    };
+    let sent = payload(1_000_000);
+    let mut bytes = proxy_protocol::encode(Version::V2, &addresses);
+    bytes.extend_from_slice(&sent);
+    let (mut reader, mut writer) = stream.split();
+    let write = async {
+        writer.write_all(&bytes).await?;
+        writer.shutdown().await
+    };
+    let mut received = Vec::new();
+    let (written, read) = timeout(TIMEOUT, async {
+        tokio::join!(write, reader.read_to_end(&mut received))
+    })
+    .await?;
+    written?;
+    read?;
+    assert!(received == sent, "received {} bytes", received.len());
+    timeout(TIMEOUT, drain).await???;
+    Ok(())
+}
+
+#[tokio::test]
+async fn connections_open_past_the_drain_deadline_are_force_closed() -> Result<()> {
+    let (echo, _) = start_echo().await?;
+    let (proxy, state) = start_proxy("tcp", &[echo], TRUSTED_PROXY_PROTOCOL).await?;
+    // one connection is proxying, the other has not sent its PROXY header yet
+    let mut proxied = TcpStream::connect(proxy).await?;
+    let addresses = Addresses {
+        source: "203.0.113.7:40000".parse()?,
+        destination: proxy,
+    };
+    proxied
+        .write_all(&proxy_protocol::encode(Version::V1, &addresses))
+        .await?;
+    proxied.write_all(b"hello").await?;
+    let mut buf = [0; 5];
+    timeout(TIMEOUT, proxied.read_exact(&mut buf)).await??;
+    let mut pending = TcpStream::connect(proxy).await?;
+    wait_active(&state, 2).await?;
+
+    let res = crate::drain(&state, Duration::from_millis(200)).await;
+    let e = res.expect_err("drain succeeded with connections open");
+    assert!(
+        e.to_string().contains("2 connections force closed"),
+        "{}",
+        e
+    );
+    for stream in [&mut proxied, &mut pending] {
+        // either a clean EOF or a reset, but no more data
+        let mut buf = Vec::new();
+        let read = timeout(TIMEOUT, stream.read_to_end(&mut buf)).await?;
+        assert!(read.is_err() || buf.is_empty());
+    }
+    assert!(state.active.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn bandwidth_is_shared_fairly_by_the_connections_of_a_listener() -> Result<()> {
+    let (echo, _) = start_echo().await?;
+    let (proxy, _) = start_proxy("tcp", &[echo], "    max_bytes_per_second: 262144").await?;
+    // use up the burst of one second, from here on the bytes are paced by the rate
+    round_trip(proxy, &payload(256 * 1024)).await?;
+    let start = Instant::now();
+    let clients: Vec<_> = (0..2)
+        .map(|_| {
+            tokio::spawn(async move {
+                let received = round_trip(proxy, &payload(256 * 1024)).await?;
+                Ok::<_, anyhow::Error>((received.len(), start.elapsed()))
+            })
+        })
+        .collect();
+    let mut finished = Vec::new();
+    for client in clients {
+        let (received, elapsed) = client.await??;
+        assert_eq!(received, 256 * 1024);
+        finished.push(elapsed);
+    }
+    finished.sort();
+    // 512KiB at 256KiB per second, a connection that got the whole bandwidth would be done
+    // after one second and leave the other to finish a second later, sharing it both take
+    // about two
+    assert!(finished[0] >= Duration::from_millis(1500), "{:?}", finished);
+    assert!(
+        finished[1] - finished[0] < Duration::from_millis(800),
+        "{:?}",
+        finished
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn bandwidth_limit_removed_by_reload_lifts_throttling() -> Result<()> {
+    let (echo, _) = start_echo().await?;
+    let limited = "    max_bytes_per_second_per_ip: 16384";
+    let (proxy, _, config) = serve_reloadable(test_config("tcp", &[echo], limited)?).await?;
+    // takes 15 seconds at the configured rate, longer than the round trip may take
+    let transfer = tokio::spawn(async move { round_trip(proxy, &payload(256 * 1024)).await });
+    sleep(Duration::from_millis(200)).await;
+    config.send_replace(Arc::new(test_config("tcp", &[echo], "")?));
+    assert_eq!(transfer.await??.len(), 256 * 1024);
+    Ok(())
+}
+
+#[tokio::test]
+async fn bandwidth_limit_added_by_reload_throttles_open_connections() -> Result<()> {
+    let (echo, _) = start_echo().await?;
+    let (proxy, _, config) = serve_reloadable(test_config("tcp", &[echo], "")?).await?;
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream.write_all(b"hello").await?;
+    let mut buf = [0; 5];
+    timeout(TIMEOUT, stream.read_exact(&mut buf)).await??;
+
+    let limited = "    max_bytes_per_second: 131072";
+    config.send_replace(Arc::new(test_config("tcp", &[echo], limited)?));
+    sleep(Duration::from_millis(100)).await;
+    let start = Instant::now();
+    let sent = payload(384 * 1024);
+    let (mut reader, mut writer) = stream.split();
+    let write = async {
+        writer.write_all(&sent).await?;
+        writer.shutdown().await
+    };
+    let mut received = Vec::new();
+    let (written, read) = timeout(TIMEOUT, async {
+        tokio::join!(write, reader.read_to_end(&mut received))
+    })
+    .await?;
+    written?;
+    read?;
+    assert_eq!(received.len(), sent.len());
+    // one second of burst, the other 256KiB take two seconds at 128KiB per second
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(1500), "{:?}", elapsed);
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_hello_split_into_one_byte_records_is_parsed() -> Result<()> {
+    let hello = client_hello("app.example.com")?;
+    let (header, fragment) = hello.split_at(5);
+    let split: Vec<u8> = fragment
+        .iter()
+        .flat_map(|byte| [header[0], header[1], header[2], 0, 1, *byte])
+        .collect();
+    let mut reader = split.as_slice();
+    let parsed = sni::read_client_hello(&mut reader).await?;
+    assert_eq!(parsed.server_name.as_deref(), Some("app.example.com"));
+    assert_eq!(parsed.bytes, split);
+    Ok(())
+}
+
+#[tokio::test]
+async fn tls_is_terminated_with_the_certificate_picked_by_sni() -> Result<()> {
+    let (echo, _) = start_echo().await?;
+    let certificates = [
+        ("default", "default.test"),
+        ("exact", "exact.wild.test"),
+        ("wildcard", "\"*.wild.test\""),
+    ]
+    .map(|(name, server_name)| {
+        format!(
+            "        - server_names: [{}]\n          cert_file: {}\n          key_file: {}\n",
+            server_name,
+            fixture(&format!("{}.pem", name)),
+            fixture(&format!("{}.key", name)),
+        )
+    });
+    let extra = format!("    tls:\n      certificates:\n{}", certificates.concat());
+    let (proxy, _) = start_proxy("tcp", &[echo], &extra).await?;
+    let connector = TlsConnector::from(tls::client_config(Some(&UpstreamTls {
+        server_name: None,
+        ca_file: Some(fixture("ca.pem")),
+    }))?);
+    let cases = [
+        ("default.test", "default"),
+        ("exact.wild.test", "exact"),
+        ("other.wild.test", "wildcard"),
+        // unknown names get the first certificate, which also lists unknown.test
+        ("unknown.test", "default"),
+    ];
+    for (server_name, certificate) in cases {
+        let stream = TcpStream::connect(proxy).await?;
+        let server_name = ServerName::try_from(server_name.to_string())?;
+        let mut stream = timeout(TIMEOUT, connector.connect(server_name.clone(), stream)).await??;
+        let served = stream.get_ref().1.peer_certificates().unwrap_or_default();
+        let expected = fs::read(fixture(&format!("{}.pem", certificate)))?;
+        let expected = rustls_pemfile::certs(&mut expected.as_slice())
+            .next()
+            .transpose()?;
+        assert_eq!(served.first(), expected.as_ref(), "{:?}", server_name);
+
+        // the echo only answers in kind if the proxy passed it the plaintext
+        stream.write_all(b"hello over tls").await?;
+        let mut buf = [0; 14];
+        timeout(TIMEOUT, stream.read_exact(&mut buf)).await??;
+        assert_eq!(&buf, b"hello over tls", "{:?}", server_name);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn tls_is_routed_by_sni_and_replayed() -> Result<()> {
+    let mut servers = Vec::new();
+    let mut counters = Vec::new();
+    for _ in 0..3 {
+        let (addr, accepted) = start_echo().await?;
+        servers.push(addr);
+        counters.push(accepted);
+    }
+    let yaml = format!(
+        r#"
+listeners:
+  - name: test
+    listen_addr: 127.0.0.1:0
+    upstream: fallback
+    sni_routes:
+      - server_names: [a.example.com]
+        upstream: exact
+      - server_names: ["*.example.org"]
+        upstream: wildcard
+upstreams:
+  exact:
+    servers: ["{}"]
+  wildcard:
+    servers: ["{}"]
+  fallback:
+    servers: ["{}"]
+"#,
+        servers[0], servers[1], servers[2]
+    );
+    let (proxy, _) = serve(Config::parse(&yaml)?).await?;
+    let cases = [
+        ("a.example.com", 0),
+        ("A.Example.com", 0),
+        ("b.example.org", 1),
+        // a wildcard matches one label only
+        ("x.b.example.org", 2),
+        ("example.net", 2),
+    ];
+    for (server_name, upstream) in cases {
+        let before: Vec<usize> = counters.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+        // the echo proves the ClientHello reached the upstream unchanged
+        let hello = client_hello(server_name)?;
+        assert!(round_trip(proxy, &hello).await? == hello, "{}", server_name);
+        let after: Vec<usize> = counters.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+        for (i, (before, after)) in before.iter().zip(&after).enumerate() {
+            let expected = if i == upstream { before + 1 } else { *before };
+            assert_eq!(
+                *after, expected,
+                "{} went to the wrong upstream",
+                server_name
+            );
+        }
+    }
+    // not TLS at all
+    assert_eq!(round_trip(proxy, b"ping").await?, b"ping");
+    assert_eq!(counters[2].load(Ordering::Relaxed), 3);
+    Ok(())
+}
+
+// the subscriber is set for this thread only, the current thread runtime keeps the
+// proxy tasks on it
+#[tokio::test(flavor = "current_thread")]
+async fn connection_span_is_exported_with_traffic() -> Result<()> {
+    let exporter = InMemorySpanExporter::default();
+    let provider = TracerProvider::builder()
+        .with_simple_exporter(exporter.clone())
+        .build();
+    let telemetry = tracing_opentelemetry::layer().with_tracer(provider.tracer("minginx-test"));
+    let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(telemetry));
+
+    let (echo, _) = start_echo().await?;
+    let (proxy, _) = start_proxy("tcp", &[echo], "").await?;
+    let received = round_trip(proxy, &payload(100_000)).await?;
+    assert_eq!(received.len(), 100_000);
+
+    let span = finished_span(&exporter).await?;
+    assert_eq!(attribute(&span, "listener"), Some("test".into()));
+    assert_eq!(attribute(&span, "upstream"), Some(echo.to_string().into()));
+    assert_eq!(
+        attribute(&span, "client_to_upstream_bytes"),
+        Some(Value::I64(100_000))
+    );
+    assert_eq!(
+        attribute(&span, "upstream_to_client_bytes"),
+        Some(Value::I64(100_000))
+    );
+    assert_eq!(attribute(&span, "close_reason"), Some("client_eof".into()));
+    assert!(attribute(&span, "client").is_some());
+    Ok(())
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn http_requests_are_written_to_the_access_log() -> Result<()> {
+    let directory = env::temp_dir().join(format!("minginx-access-{}", std::process::id()));
+    fs::create_dir_all(&directory)?;
+    let config = AccessLogConfig {
+        directory: directory.display().to_string(),
+        file_name_prefix: "access.log".to_string(),
+    };
+    let (layer, guard) = access_log::layer(&config);
+    let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+
+    let upstream = start_http("hello").await?;
+    let (proxy, _) = start_proxy("http", &[upstream], "").await?;
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream
+        .write_all(b"GET /reports?year=2024 HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut res = String::new();
+    timeout(TIMEOUT, stream.read_to_string(&mut res)).await??;
+    assert!(res.ends_with("hello"), "unexpected response: {}", res);
+
+    // written when the response body is done, the client may see it a moment earlier
+    let record = timeout(TIMEOUT, async {
+        loop {
+            if let Some(record) = access_records(&directory)?.pop() {
+                return Ok::<_, anyhow::Error>(record);
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await??;
+    drop(guard);
+    fs::remove_dir_all(&directory)?;
+    assert_eq!(record["message"], "request completed");
+    assert_eq!(record["listener"], "test");
+    assert_eq!(record["method"], "GET");
+    assert_eq!(record["path"], "/reports");
+    assert_eq!(record["status"], 200);
+    assert_eq!(record["upstream"], upstream.to_string());
+    assert_eq!(record["client_to_upstream_bytes"], 0);
+    assert_eq!(record["upstream_to_client_bytes"], 5);
+    assert!(record["duration_ms"].is_u64());
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_tcp_connections_are_written_to_the_access_log() -> Result<()> {
+    let (echo, _) = start_echo().await?;
+    let tls = format!(
+        "    tls:\n      certificates:\n        - cert_file: {}\n          key_file: {}\n",
+        fixture("default.pem"),
+        fixture("default.key")
+    );
+    let cases = [
+        (
+            "denied",
+            "    deny: [127.0.0.0/8]",
+            "rejected",
+            "denied by acl",
+        ),
+        ("no server", "", "error", "No available server"),
+        (
+            "bad proxy header",
+            TRUSTED_PROXY_PROTOCOL,
+            "error",
+            "Missing PROXY protocol header",
+        ),
+        (
+            "bad handshake",
+            tls.as_str(),
+            "error",
+            "TLS handshake failed",
+        ),
+    ];
+    for (i, (case, extra, close_reason, error)) in cases.into_iter().enumerate() {
+        let directory =
+            env::temp_dir().join(format!("minginx-failed-{}-{}", std::process::id(), i));
+        fs::create_dir_all(&directory)?;
+        let config = AccessLogConfig {
+            directory: directory.display().to_string(),
+            file_name_prefix: "access.log".to_string(),
+        };
+        let (layer, guard) = access_log::layer(&config);
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+
+        let (proxy, state) = start_proxy("tcp", &[echo], extra).await?;
+        if case == "no server" {
+            state
+                .servers
+                .set_status(&echo.to_string(), ServerStatus::Disabled);
+        }
+        let mut stream = TcpStream::connect(proxy).await?;
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await?;
+        let _ = timeout(TIMEOUT, stream.read_to_end(&mut Vec::new())).await?;
+
+        let record = timeout(TIMEOUT, async {
+            loop {
+                if let Some(record) = access_records(&directory)?.pop() {
+                    return Ok::<_, anyhow::Error>(record);
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await??;
+        drop(guard);
+        fs::remove_dir_all(&directory)?;
+        assert_eq!(record["message"], "connection closed", "{}", case);
+        assert_eq!(record["listener"], "test", "{}", case);
+        assert_eq!(record["close_reason"], close_reason, "{}", case);
+        assert!(
+            record["error"].as_str().is_some_and(|e| e.contains(error)),
+            "{}: {}",
+            case,
+            record
+        );
+        assert!(record.get("upstream").is_none(), "{}: {}", case, record);
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn failed_connection_span_has_error_status() -> Result<()> {
+    let (echo, _) = start_echo().await?;
+    let (down, _reserved) = closed_addr()?;
+    let cases = [
+        ("upstream down", down, "", "error", "Connection refused"),
+        ("no server", echo, "", "error", "No available server"),
+        (
+            "denied",
+            echo,
+            "    deny: [127.0.0.0/8]",
+            "rejected",
+            "denied by acl",
+        ),
+    ];
+    for (case, server, extra, close_reason, message) in cases {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let telemetry = tracing_opentelemetry::layer().with_tracer(provider.tracer("minginx-test"));
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(telemetry));
+
+        let (proxy, state) = start_proxy("tcp", &[server], extra).await?;
+        if case == "no server" {
+            state
+                .servers
+                .set_status(&server.to_string(), ServerStatus::Disabled);
+        }
+        let mut stream = TcpStream::connect(proxy).await?;
+        let _ = timeout(TIMEOUT, stream.read_to_end(&mut Vec::new())).await?;
+
+        let span = finished_span(&exporter).await?;
+        assert_eq!(
+            attribute(&span, "close_reason"),
+            Some(close_reason.into()),
+            "{}",
+            case
+        );
+        assert!(
+            matches!(&span.status, Status::Error { description } if description.contains(message)),
+            "{}: unexpected status: {:?}",
+            case,
+            span.status
+        );
+    }
+    Ok(())
+}
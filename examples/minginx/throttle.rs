@@ -0,0 +1,170 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use tokio::{
+    sync::Mutex,
+    time::{sleep, Instant},
+};
+
+use crate::config::{Config, ListenerConfig};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    ClientToUpstream,
+    UpstreamToClient,
+}
+
+/// bytes per second shared by several connections, 0 means unlimited
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: AtomicU64,
+    tokens: Mutex<Tokens>,
+}
+
+#[derive(Debug)]
+struct Tokens {
+    /// negative while the bytes already sent are ahead of the rate
+    available: f64,
+    updated: Instant,
+}
+
+/// the buckets of all listeners and clients, their rates follow config reloads
+#[derive(Debug, Default)]
+pub struct Bandwidth {
+    listeners: DashMap<(String, Direction), Arc<TokenBucket>>,
+    clients: DashMap<(String, IpAddr, Direction), Arc<TokenBucket>>,
+}
+
+/// the buckets one connection draws from, the per client ones go away with the last connection
+#[derive(Debug)]
+pub struct Throttle {
+    buckets: Vec<(Direction, Arc<TokenBucket>)>,
+    bandwidth: Arc<Bandwidth>,
+    listener: String,
+    ip: IpAddr,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: AtomicU64::new(rate),
+            tokens: Mutex::new(Tokens {
+                available: rate as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// wait until `n` bytes fit into the rate, the lock is fair so the waiting
+    /// connections take turns instead of the busiest one taking it all
+    pub async fn consume(&self, n: usize) {
+        // unlimited buckets are not worth the lock
+        if self.rate() == 0 {
+            return;
+        }
+        let mut tokens = self.tokens.lock().await;
+        let rate = self.rate();
+        if rate == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let refill = now.duration_since(tokens.updated).as_secs_f64() * rate as f64;
+        // at most one second of burst
+        tokens.available = (tokens.available + refill).min(rate as f64) - n as f64;
+        tokens.updated = now;
+        if tokens.available < 0.0 {
+            sleep(Duration::from_secs_f64(-tokens.available / rate as f64)).await;
+        }
+    }
+
+    fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+}
+
+impl Bandwidth {
+    /// the buckets for a new connection of `listener` from `ip`, they exist even without
+    /// limits so a reload that adds one throttles the connection too
+    pub fn throttle(self: &Arc<Self>, listener: &ListenerConfig, ip: IpAddr) -> Throttle {
+        let mut throttle = Throttle {
+            buckets: Vec::new(),
+            bandwidth: self.clone(),
+            listener: listener.name.clone(),
+            ip,
+        };
+        for direction in [Direction::ClientToUpstream, Direction::UpstreamToClient] {
+            let rate = listener.max_bytes_per_second.unwrap_or(0);
+            let bucket = self
+                .listeners
+                .entry((listener.name.clone(), direction))
+                .or_insert_with(|| Arc::new(TokenBucket::new(rate)))
+                .clone();
+            throttle.buckets.push((direction, bucket));
+            let rate = listener.max_bytes_per_second_per_ip.unwrap_or(0);
+            let bucket = self
+                .clients
+                .entry((listener.name.clone(), ip, direction))
+                .or_insert_with(|| Arc::new(TokenBucket::new(rate)))
+                .clone();
+            throttle.buckets.push((direction, bucket));
+        }
+        throttle
+    }
+
+    /// apply the rates of a reloaded config to the buckets in use, a limit added or removed
+    /// by the reload applies to the open connections, except the ones forwarded with splice
+    pub fn configure(&self, config: &Config) {
+        for entry in self.listeners.iter() {
+            let (name, _) = entry.key();
+            let rate = config.listener(name).and_then(|l| l.max_bytes_per_second);
+            entry.set_rate(rate.unwrap_or(0));
+        }
+        for entry in self.clients.iter() {
+            let (name, _, _) = entry.key();
+            let rate = config
+                .listener(name)
+                .and_then(|l| l.max_bytes_per_second_per_ip);
+            entry.set_rate(rate.unwrap_or(0));
+        }
+    }
+}
+
+impl Throttle {
+    /// no limit at the moment, a reload may still add one
+    pub fn is_unlimited(&self) -> bool {
+        self.buckets.iter().all(|(_, bucket)| bucket.rate() == 0)
+    }
+
+    pub async fn consume(&self, direction: Direction, n: usize) {
+        for (d, bucket) in &self.buckets {
+            if *d == direction {
+                bucket.consume(n).await;
+            }
+        }
+    }
+}
+
+impl Drop for Throttle {
+    fn drop(&mut self) {
+        self.buckets.clear();
+        for direction in [Direction::ClientToUpstream, Direction::UpstreamToClient] {
+            // only the map holds the bucket once the last connection of the client is gone
+            self.bandwidth
+                .clients
+                .remove_if(&(self.listener.clone(), self.ip, direction), |_, bucket| {
+                    Arc::strong_count(bucket) == 1
+                });
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use anyhow::{anyhow, Context as _, Result};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::TlsStream;
+
+use crate::config::{ListenerTls, UpstreamTls};
+
+/// a connection on either side of the proxy, plaintext or TLS
+#[derive(Debug)]
+pub enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// select the certificate by SNI, exact names first then `*.` wildcards,
+/// falling back to the first configured certificate
+#[derive(Debug)]
+struct SniResolver {
+    certs: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+pub fn server_config(tls: &ListenerTls) -> Result<Arc<ServerConfig>> {
+    let provider = Arc::new(ring::default_provider());
+    let resolver = SniResolver::try_new(tls, &provider)?;
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    Ok(Arc::new(config))
+}
+
+pub fn client_config(tls: Option<&UpstreamTls>) -> Result<Arc<ClientConfig>> {
+    let roots = match tls.and_then(|tls| tls.ca_file.as_deref()) {
+        Some(ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_file)? {
+                roots.add(cert)?;
+            }
+            roots
+        }
+        None => RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        },
+    };
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// name to verify the upstream certificate, the configured one or the host of the server address
+pub fn server_name(tls: &UpstreamTls, addr: &str) -> Result<ServerName<'static>> {
+    let name = match &tls.server_name {
+        Some(name) => name.as_str(),
+        None => addr
+            .rsplit_once(':')
+            .map_or(addr, |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']'),
+    };
+    ServerName::try_from(name.to_string()).with_context(|| format!("Invalid server name: {}", name))
+}
+
+impl SniResolver {
+    fn try_new(tls: &ListenerTls, provider: &CryptoProvider) -> Result<Self> {
+        let mut certs = HashMap::new();
+        let mut default = None;
+        for cert in &tls.certificates {
+            let key = CertifiedKey::from_der(
+                load_certs(&cert.cert_file)?,
+                load_key(&cert.key_file)?,
+                provider,
+            )?;
+            let key = Arc::new(key);
+            for name in &cert.server_names {
+                certs.insert(name.to_ascii_lowercase(), key.clone());
+            }
+            default.get_or_insert(key);
+        }
+        let default = default.ok_or_else(|| anyhow!("No certificate configured"))?;
+        Ok(Self { certs, default })
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let Some(name) = client_hello.server_name() else {
+            return Some(self.default.clone());
+        };
+        let name = name.to_ascii_lowercase();
+        let wildcard = name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{}", parent));
+        self.certs
+            .get(&name)
+            .or_else(|| wildcard.and_then(|wildcard| self.certs.get(&wildcard)))
+            .cloned()
+            .or_else(|| Some(self.default.clone()))
+    }
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader =
+        BufReader::new(File::open(path).with_context(|| format!("Can not open {}", path))?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", path));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let mut reader =
+        BufReader::new(File::open(path).with_context(|| format!("Can not open {}", path))?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| anyhow!("No private key found in {}", path))
+}
+
+impl MaybeTlsStream {
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Self::Tls(_))
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
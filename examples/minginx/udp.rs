@@ -0,0 +1,278 @@
+use std::{
+    fmt,
+    future::pending,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use dashmap::DashMap;
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    sync::{mpsc, watch},
+    time::{sleep, Instant},
+};
+use tracing::{error, info, warn, Instrument};
+
+use crate::{
+    access_log::AccessLog,
+    config::Config,
+    limits::{IpSlot, Slot},
+    proxy::{Activity, CloseReason, ProxyOptions},
+    state::{State, Tracked},
+    telemetry,
+};
+
+/// large enough for any datagram
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// udp has no close, a session without `idle_timeout` expires after this
+const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// datagrams waiting for the session to send them, more are dropped like on a full socket buffer
+const SESSION_QUEUE_SIZE: usize = 256;
+
+/// at most one warning about dropped datagrams in this time, spoofed sources would flood
+/// the log otherwise
+const DROP_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+type Sessions = DashMap<SocketAddr, Arc<Session>>;
+
+/// one client, its datagrams are sent from an upstream socket of its own so the replies
+/// can be told apart
+#[derive(Debug)]
+struct Session {
+    connection: Tracked,
+    datagrams: mpsc::Sender<Bytes>,
+    activity: Activity,
+    _slots: (IpSlot, Slot),
+}
+
+/// the warnings the receive loop logs about the datagrams it drops
+#[derive(Debug, Default)]
+struct DropWarnings {
+    last: Option<Instant>,
+    suppressed: u64,
+}
+
+/// forward the datagrams received on `socket`, each client address gets its own session,
+/// the loop never waits for an upstream so one slow session can not hold up the others
+pub async fn serve(
+    socket: UdpSocket,
+    config: watch::Receiver<Arc<Config>>,
+    state: Arc<State>,
+    name: String,
+) -> Result<()> {
+    let socket = Arc::new(socket);
+    let sessions = Arc::new(Sessions::new());
+    let mut drops = DropWarnings::default();
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        let (n, addr) = socket.recv_from(&mut buf).await?;
+        let session = match sessions.get(&addr) {
+            Some(session) => session.clone(),
+            // while draining only the existing sessions are served
+            None if state.shutdown.is_cancelled() => continue,
+            None => {
+                // the session keeps using this config even if it is reloaded meanwhile
+                let config = config.borrow().clone();
+                match open_session(&config, &state, &name, addr) {
+                    Ok((session, datagrams, options)) => {
+                        sessions.insert(addr, session.clone());
+                        let span = telemetry::connection_span(addr, &name);
+                        tokio::spawn(
+                            run_session(
+                                session.clone(),
+                                datagrams,
+                                socket.clone(),
+                                sessions.clone(),
+                                state.clone(),
+                                options,
+                                name.clone(),
+                            )
+                            .instrument(span),
+                        );
+                        session
+                    }
+                    Err(e) => {
+                        drops.warn(addr, format_args!("{:#}", e));
+                        continue;
+                    }
+                }
+            }
+        };
+        let datagram = Bytes::copy_from_slice(&buf[..n]);
+        if session.datagrams.try_send(datagram).is_err() {
+            drops.warn(addr, "the session is busy or closed");
+        }
+    }
+}
+
+/// apply the same ACL and limits as for tcp connections and pick the next server,
+/// the session connects to it once it runs
+fn open_session(
+    config: &Config,
+    state: &State,
+    name: &str,
+    addr: SocketAddr,
+) -> Result<(Arc<Session>, mpsc::Receiver<Bytes>, ProxyOptions)> {
+    let connections = &state.connections;
+    let listener_config = config
+        .listener(name)
+        .with_context(|| format!("Listener {} is not configured", name))?;
+    let (upstream_name, upstream) = listener_config
+        .upstream
+        .as_deref()
+        .and_then(|name| Some((name, config.upstream(name)?)))
+        .with_context(|| format!("No upstream configured for listener: {}", name))?;
+
+    if !listener_config.allows(addr.ip()) {
+        bail!("denied by acl");
+    }
+    let ip_slot = connections
+        .try_acquire_ip(
+            &listener_config.name,
+            addr.ip(),
+            listener_config.max_connections_per_ip,
+        )
+        .context("too many sessions from the client")?;
+    // a datagram can not wait for a free slot, it is dropped whatever `when_full` says
+    let slot = connections
+        .try_acquire(config.max_connections.map(|m| m.limit))
+        .with_context(|| format!("too many connections ({})", connections.total()))?;
+    let upstream_addr = upstream
+        .next_server(&state.servers)
+        .with_context(|| format!("No available server in upstream: {}", upstream_name))?;
+
+    let connection = state.active.register(addr, name);
+    connection.set_upstream(upstream_addr);
+    let (sender, receiver) = mpsc::channel(SESSION_QUEUE_SIZE);
+    let session = Session {
+        connection,
+        datagrams: sender,
+        activity: Activity::new(),
+        _slots: (ip_slot, slot),
+    };
+    Ok((Arc::new(session), receiver, listener_config.proxy_options()))
+}
+
+async fn connect(upstream_addr: &str) -> Result<UdpSocket> {
+    let server = lookup_host(upstream_addr)
+        .await?
+        .next()
+        .with_context(|| format!("Can not resolve upstream: {}", upstream_addr))?;
+    let bind_addr = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(server).await?;
+    Ok(socket)
+}
+
+/// connect to the upstream, then pass datagrams both ways until the session expires,
+/// the datagrams that arrive meanwhile wait in the queue
+async fn run_session(
+    session: Arc<Session>,
+    datagrams: mpsc::Receiver<Bytes>,
+    socket: Arc<UdpSocket>,
+    sessions: Arc<Sessions>,
+    state: Arc<State>,
+    options: ProxyOptions,
+    listener: String,
+) {
+    let client = session.connection.client();
+    let upstream_addr = session.connection.upstream().unwrap_or_default();
+    let mut access_log = AccessLog::new(&listener);
+    access_log.set_upstream(&upstream_addr);
+    let res = connect(&upstream_addr).await;
+    state.servers.record(&upstream_addr, &res);
+    let close_reason = match res {
+        Ok(upstream) => {
+            info!("Proxy {} to upstream: {}", client, upstream_addr);
+            forward(&session, &upstream, datagrams, &socket, options).await
+        }
+        Err(e) => CloseReason::Error(e),
+    };
+    sessions.remove_if(&client, |_, s| Arc::ptr_eq(s, &session));
+    if let CloseReason::Error(e) = &close_reason {
+        error!("Error proxy datagrams: {:?}", e);
+    }
+    telemetry::record_close(&session.connection, Some(&close_reason));
+    access_log.finish(&session.connection, &close_reason);
+}
+
+async fn forward(
+    session: &Session,
+    upstream: &UdpSocket,
+    mut datagrams: mpsc::Receiver<Bytes>,
+    socket: &UdpSocket,
+    options: ProxyOptions,
+) -> CloseReason {
+    let idle = session.activity.idle(Some(
+        options.idle_timeout.unwrap_or(DEFAULT_SESSION_TIMEOUT),
+    ));
+    let max_lifetime = async {
+        match options.max_lifetime {
+            Some(max_lifetime) => sleep(max_lifetime).await,
+            None => pending().await,
+        }
+    };
+    tokio::pin!(idle, max_lifetime);
+
+    let traffic = &session.connection.traffic;
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            Some(datagram) = datagrams.recv() => match upstream.send(&datagram).await {
+                Ok(sent) => {
+                    traffic.client_to_upstream.fetch_add(sent as u64, Ordering::Relaxed);
+                    session.activity.touch();
+                }
+                Err(e) => break CloseReason::Error(e.into()),
+            },
+            res = upstream.recv(&mut buf) => {
+                let res = match res {
+                    Ok(n) => socket.send_to(&buf[..n], session.connection.client()).await,
+                    Err(e) => Err(e),
+                };
+                match res {
+                    Ok(sent) => {
+                        traffic.upstream_to_client.fetch_add(sent as u64, Ordering::Relaxed);
+                        session.activity.touch();
+                    }
+                    Err(e) => break CloseReason::Error(e.into()),
+                }
+            },
+            _ = &mut idle => break CloseReason::IdleTimeout,
+            _ = &mut max_lifetime => break CloseReason::MaxLifetime,
+            reason = session.connection.closed() => break reason.into(),
+        }
+    }
+}
+
+impl DropWarnings {
+    fn warn(&mut self, addr: SocketAddr, reason: impl fmt::Display) {
+        let now = Instant::now();
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) < DROP_WARNING_INTERVAL)
+        {
+            self.suppressed += 1;
+            return;
+        }
+        if self.suppressed > 0 {
+            warn!(
+                "Drop datagram from {}: {}, {} more dropped since the last warning",
+                addr, reason, self.suppressed
+            );
+        } else {
+            warn!("Drop datagram from {}: {}", addr, reason);
+        }
+        self.last = Some(now);
+        self.suppressed = 0;
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use hashlink::LruCache;
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::{error::AppError, storage::UrlRow};
+
+/// recently looked up links by id, `None` for ids that do not exist, so a burst of
+/// requests for a popular or a made up id reaches the storage once
+#[derive(Debug)]
+pub struct UrlCache {
+    entries: Mutex<LruCache<String, Cached>>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    /// bumped by every invalidation, a lookup that raced with one is not cached
+    generation: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Cached {
+    row: Option<UrlRow>,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub capacity: usize,
+}
+
+impl UrlCache {
+    /// a capacity of 0 turns the cache off, every lookup is a miss
+    pub fn new(capacity: usize, ttl: Duration, negative_ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            ttl,
+            negative_ttl,
+            generation: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// the cached row of `id`, or the one `load` finds, which is cached then
+    pub async fn get_or_load<F>(&self, id: &str, load: F) -> Result<Option<UrlRow>, AppError>
+    where
+        F: Future<Output = Result<Option<UrlRow>, AppError>>,
+    {
+        if let Some(row) = self.get(id) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(row);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let generation = self.generation.load(Ordering::Acquire);
+        let row = load.await?;
+        let ttl = match row {
+            Some(_) => self.ttl,
+            None => self.negative_ttl,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if entries.capacity() > 0 && self.generation.load(Ordering::Acquire) == generation {
+            let cached = Cached {
+                row: row.clone(),
+                expires_at: Instant::now() + ttl,
+            };
+            entries.insert(id.to_string(), cached);
+        }
+        Ok(row)
+    }
+
+    /// forget `id` after it was stored or deleted
+    pub fn invalidate(&self, id: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(id);
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: entries.len(),
+            capacity: entries.capacity(),
+        }
+    }
+
+    /// `Some(None)` for an id known not to exist
+    fn get(&self, id: &str) -> Option<Option<UrlRow>> {
+        let mut entries = self.entries.lock().unwrap();
+        let cached = entries.get(id)?;
+        if cached.expires_at <= Instant::now() {
+            entries.remove(id);
+            return None;
+        }
+        Some(cached.row.clone())
+    }
+}
@@ -0,0 +1,156 @@
+use std::{env, fs, net::SocketAddr, str::FromStr};
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::Deserialize;
+use url::Url;
+
+use crate::{IdGenerator, ALLOWED_SCHEMES, ID_ALPHABET};
+
+/// read when no file is given as the first argument, it may be missing
+const CONFIG_PATH: &str = "examples/shortener/shortener.yml";
+
+/// settings in the environment take precedence over the config file, e.g. `SHORTENER_DB_URL`
+const ENV_PREFIX: &str = "SHORTENER_";
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// where the short links are served to the world, e.g. `https://sho.rt`,
+    /// `http://` and the listen address if not set
+    pub base_url: Option<String>,
+    pub listen_addr: SocketAddr,
+    /// `postgres://…`, `sqlite:…` or `memory:` for links that live as long as the process
+    pub db_url: String,
+    pub db_pool_size: u32,
+    pub id_len: usize,
+    pub id_alphabet: String,
+    /// most servers ignore the order of query params, but not all of them
+    pub sort_query_params: bool,
+    pub migrations: Migrations,
+    /// links kept in memory for redirects, 0 turns the cache off
+    pub cache_capacity: usize,
+}
+
+/// what to do at startup about migrations the database has not seen yet
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Migrations {
+    #[default]
+    Run,
+    /// refuse to start instead, for deployments that migrate before rolling out
+    Check,
+}
+
+impl Config {
+    /// the file, or `CONFIG_PATH` if it exists, then the environment over it
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let optional = path.is_none();
+        let path = path.unwrap_or(CONFIG_PATH);
+        let config = match fs::read_to_string(path) {
+            Ok(content) => serde_yaml::from_str(&content)
+                .with_context(|| format!("Invalid config file: {}", path))?,
+            Err(e) if optional && e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e).with_context(|| format!("Can not read config file: {}", path)),
+        };
+        let config = config.with_env(|name| env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// override the settings found in `var`, named like the fields in upper case
+    pub fn with_env(mut self, var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        fn parse<T>(var: &impl Fn(&str) -> Option<String>, field: &str) -> Result<Option<T>>
+        where
+            T: FromStr,
+            T::Err: Into<anyhow::Error>,
+        {
+            let name = format!("{}{}", ENV_PREFIX, field.to_ascii_uppercase());
+            var(&name)
+                .map(|value| value.parse().map_err(Into::into))
+                .transpose()
+                .with_context(|| format!("Invalid environment variable: {}", name))
+        }
+        if let Some(base_url) = parse(&var, "base_url")? {
+            self.base_url = Some(base_url);
+        }
+        self.listen_addr = parse(&var, "listen_addr")?.unwrap_or(self.listen_addr);
+        self.db_url = parse(&var, "db_url")?.unwrap_or(self.db_url);
+        self.db_pool_size = parse(&var, "db_pool_size")?.unwrap_or(self.db_pool_size);
+        self.id_len = parse(&var, "id_len")?.unwrap_or(self.id_len);
+        self.id_alphabet = parse(&var, "id_alphabet")?.unwrap_or(self.id_alphabet);
+        self.sort_query_params =
+            parse(&var, "sort_query_params")?.unwrap_or(self.sort_query_params);
+        self.migrations = parse(&var, "migrations")?.unwrap_or(self.migrations);
+        self.cache_capacity = parse(&var, "cache_capacity")?.unwrap_or(self.cache_capacity);
+        Ok(self)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.db_pool_size > 0, "db_pool_size must be positive");
+        if let Some(base_url) = &self.base_url {
+            let url =
+                Url::parse(base_url).with_context(|| format!("Invalid base_url: {}", base_url))?;
+            if !ALLOWED_SCHEMES.contains(&url.scheme()) || url.host_str().is_none() {
+                bail!(
+                    "base_url must be an http or https url with a host: {}",
+                    base_url
+                );
+            }
+            if url.query().is_some() || url.fragment().is_some() {
+                bail!("base_url can not have a query or fragment: {}", base_url);
+            }
+        }
+        let alphabet: Vec<char> = self.id_alphabet.chars().collect();
+        IdGenerator::nanoid(self.id_len, &alphabet)?;
+        Ok(())
+    }
+
+    /// short links are `{base_url}/{id}`, a path in the base url is kept
+    pub fn base_url(&self) -> String {
+        match &self.base_url {
+            Some(base_url) => base_url.trim_end_matches('/').to_string(),
+            None => format!("http://{}", self.listen_addr),
+        }
+    }
+
+    /// the database url without the password, to be logged
+    pub fn redacted_db_url(&self) -> String {
+        match Url::parse(&self.db_url) {
+            Ok(mut url) => {
+                if url.password().is_some() {
+                    let _ = url.set_password(Some("***"));
+                }
+                url.into()
+            }
+            Err(_) => "<invalid url>".to_string(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            base_url: None,
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 1234)),
+            db_url: "postgres://localhost:5432/shortener".to_string(),
+            db_pool_size: 100,
+            id_len: 6,
+            id_alphabet: ID_ALPHABET.iter().collect(),
+            sort_query_params: false,
+            migrations: Migrations::Run,
+            cache_capacity: 10_000,
+        }
+    }
+}
+
+impl FromStr for Migrations {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "run" => Ok(Self::Run),
+            "check" => Ok(Self::Check),
+            _ => bail!("unknown migrations mode {}, use run or check", s),
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use axum::{
+    extract::rejection::JsonRejection,
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::StatusCode;
+use serde::Serialize;
+use thiserror::Error;
+use tracing::error;
+
+/// what went wrong in a request, the response tells the client only as much as it can act on
+#[derive(Debug, Error)]
+pub enum AppError {
+    #[error("link {0} not found")]
+    NotFound(String),
+    #[error("{0}")]
+    InvalidInput(String),
+    /// the alias or the url already belongs to another short url
+    #[error("{0}")]
+    Conflict(String),
+    /// the link expired or reached its max clicks
+    #[error("link {0} expired")]
+    Gone(String),
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+impl AppError {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::Gone(_) => StatusCode::GONE,
+            Self::Database(sqlx::Error::PoolTimedOut) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Database(_) | Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        // the cause of a server side error is for the logs, not for the client
+        let error = if status.is_server_error() {
+            error!("Error handling request: {:?}", self);
+            status
+                .canonical_reason()
+                .unwrap_or("internal error")
+                .to_ascii_lowercase()
+        } else {
+            self.to_string()
+        };
+        (status, Json(ErrorResponse { error })).into_response()
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::InvalidInput(rejection.body_text())
+    }
+}
@@ -0,0 +1,558 @@
+mod cache;
+mod config;
+mod error;
+mod storage;
+
+#[cfg(test)]
+mod tests;
+
+use std::{
+    env, fmt,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, ensure, Result};
+use axum::{
+    extract::{rejection::JsonRejection, ConnectInfo, Path, State},
+    response::{IntoResponse, Redirect},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use tokio::{
+    net::TcpListener,
+    sync::mpsc::{self, error::TrySendError},
+    time::{interval, timeout_at, Instant},
+};
+use tracing::{error, info, instrument, level_filters::LevelFilter, warn};
+use tracing_subscriber::{
+    fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt, Layer,
+};
+use url::Url;
+
+use crate::{
+    cache::{CacheStats, UrlCache},
+    config::Config,
+    error::AppError,
+    storage::{Storage, UrlRow},
+};
+
+const ID_ALPHABET: [char; 64] = nanoid::alphabet::SAFE;
+
+/// generated ids tried before giving up, a collision is rare unless the ids run out
+const MAX_ID_ATTEMPTS: usize = 5;
+
+/// how often expired and used up links are deleted
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// how stale a cached link may get, the cache of another instance misses changes
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// kept short, an unknown id may be taken as an alias any moment
+const NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// clicks waiting to be written, more are dropped instead of slowing down redirects
+const CLICK_QUEUE_SIZE: usize = 10_000;
+
+const CLICK_BATCH_SIZE: usize = 500;
+
+/// the longest a click waits for others to share its insert
+const CLICK_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+const TOP_REFERRERS: i64 = 10;
+
+const MIN_ALIAS_LEN: usize = 3;
+
+const MAX_ALIAS_LEN: usize = 32;
+
+/// paths the service uses or may use itself, compared case-insensitively
+const RESERVED_ALIASES: &[&str] = &["admin", "api", "health", "metrics", "static", "stats"];
+
+/// what common browsers and proxies accept without trouble
+const MAX_URL_LEN: usize = 2048;
+
+const ALLOWED_SCHEMES: &[&str] = &["http", "https"];
+
+#[derive(Debug, Deserialize)]
+struct ShortenRequest {
+    url: String,
+    /// custom short code instead of a generated one, e.g. `q3-report`
+    #[serde(default)]
+    alias: Option<String>,
+    #[serde(flatten)]
+    limits: Limits,
+}
+
+/// a link with limits is never shared with other requests for the same url
+#[derive(Debug, Default, Deserialize)]
+struct Limits {
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    max_clicks: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ShortenResponse {
+    url: String,
+}
+
+#[derive(Debug, Serialize)]
+struct MetricsResponse {
+    cache: CacheStats,
+}
+
+#[derive(Debug, Serialize)]
+struct StatsResponse {
+    id: String,
+    total_clicks: i64,
+    daily: Vec<DailyClicks>,
+    top_referrers: Vec<ReferrerClicks>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+struct DailyClicks {
+    date: NaiveDate,
+    clicks: i64,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+struct ReferrerClicks {
+    referrer: String,
+    clicks: i64,
+}
+
+/// one redirect, the client address is cut down to its network
+#[derive(Debug, Clone)]
+struct Click {
+    url_id: String,
+    clicked_at: DateTime<Utc>,
+    referrer: Option<String>,
+    user_agent: Option<String>,
+    client_ip: String,
+}
+
+#[derive(Debug, Clone)]
+struct AppState {
+    storage: Arc<dyn Storage>,
+    cache: Arc<UrlCache>,
+    ids: IdGenerator,
+    clicks: mpsc::Sender<Click>,
+    base_url: Arc<str>,
+    sort_query_params: bool,
+}
+
+/// random short codes for urls without an alias
+#[derive(Clone)]
+struct IdGenerator {
+    generate: Arc<dyn Fn() -> String + Send + Sync>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let logs_layer = tracing_subscriber::fmt::Layer::new()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_filter(LevelFilter::INFO);
+
+    tracing_subscriber::registry().with(logs_layer).init();
+
+    let config = Config::load(env::args().nth(1).as_deref())?;
+
+    let listener = TcpListener::bind(config.listen_addr).await?;
+    info!("Server listening on: {}", config.listen_addr);
+
+    let app_state = AppState::try_new(&config).await?;
+
+    info!("Connected to database: {}", config.redacted_db_url());
+
+    tokio::spawn(app_state.clone().purge_expired());
+
+    axum::serve(
+        listener,
+        app(app_state).into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
+
+    Ok(())
+}
+
+fn app(state: AppState) -> Router {
+    Router::new()
+        .route("/", post(shorten))
+        .route("/metrics", get(metrics))
+        .route("/:id", get(redirect))
+        .route("/:id/stats", get(stats))
+        .with_state(state)
+}
+
+impl AppState {
+    async fn try_new(config: &Config) -> Result<Self> {
+        let storage = storage::connect(config).await?;
+        Self::new(config, storage)
+    }
+
+    fn new(config: &Config, storage: Arc<dyn Storage>) -> Result<Self> {
+        let alphabet: Vec<char> = config.id_alphabet.chars().collect();
+        let ids = IdGenerator::nanoid(config.id_len, &alphabet)?;
+        let (clicks, receiver) = mpsc::channel(CLICK_QUEUE_SIZE);
+        tokio::spawn(record_clicks(storage.clone(), receiver));
+        let cache = UrlCache::new(config.cache_capacity, CACHE_TTL, NEGATIVE_CACHE_TTL);
+        Ok(Self {
+            storage,
+            cache: Arc::new(cache),
+            ids,
+            clicks,
+            base_url: config.base_url().into(),
+            sort_query_params: config.sort_query_params,
+        })
+    }
+
+    async fn shorten(&self, url: &str, limits: &Limits) -> Result<String, AppError> {
+        let id = with_unique_id(&self.ids, MAX_ID_ATTEMPTS, |id| async move {
+            self.storage.insert(&id, url, limits).await
+        })
+        .await?;
+        // the id may be cached as unknown
+        self.cache.invalidate(&id);
+        Ok(id)
+    }
+
+    /// store `url` under `alias`, repeating the same request returns the same alias
+    async fn shorten_with_alias(
+        &self,
+        url: &str,
+        alias: &str,
+        limits: &Limits,
+    ) -> Result<String, AppError> {
+        if self.storage.insert_alias(alias, url, limits).await? {
+            self.cache.invalidate(alias);
+            return Ok(alias.to_string());
+        }
+        match self.storage.get(alias).await? {
+            Some(row) if row.url == url => Ok(row.id),
+            Some(_) => {
+                let msg = format!("alias {} is already taken", alias);
+                Err(AppError::Conflict(msg))
+            }
+            None => Err(anyhow!("alias {} conflicts with a row that is gone", alias).into()),
+        }
+    }
+
+    /// look up a link and count the click if it has a click limit, fails with `Gone`
+    /// once it expired or is used up
+    async fn get_url(&self, id: &str) -> Result<UrlRow, AppError> {
+        let row = self
+            .cache
+            .get_or_load(id, self.storage.get(id))
+            .await?
+            .ok_or_else(|| AppError::NotFound(id.to_string()))?;
+        if row
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+        {
+            return Err(AppError::Gone(id.to_string()));
+        }
+        if row.max_clicks.is_some() && !self.storage.claim_click(id).await? {
+            return Err(AppError::Gone(id.to_string()));
+        }
+        Ok(row)
+    }
+
+    /// clicks of all time, by day and by referrer
+    async fn stats(&self, id: &str) -> Result<StatsResponse, AppError> {
+        if self.storage.get(id).await?.is_none() {
+            return Err(AppError::NotFound(id.to_string()));
+        }
+        let daily = self.storage.daily_clicks(id).await?;
+        let top_referrers = self.storage.top_referrers(id, TOP_REFERRERS).await?;
+        Ok(StatsResponse {
+            id: id.to_string(),
+            total_clicks: daily.iter().map(|day| day.clicks).sum(),
+            daily,
+            top_referrers,
+        })
+    }
+
+    /// queue a click for `record_clicks`, the redirect does not wait for the database
+    fn record_click(&self, click: Click) {
+        match self.clicks.try_send(click) {
+            Ok(()) => {}
+            Err(TrySendError::Full(click)) => {
+                warn!("Dropped click on {}, the queue is full", click.url_id)
+            }
+            Err(TrySendError::Closed(click)) => {
+                error!("Dropped click on {}, the writer is gone", click.url_id)
+            }
+        }
+    }
+
+    /// drop the urls of expired and used up links, their ids are never reused and their
+    /// clicks stay for the stats
+    async fn purge_expired(self) {
+        let mut interval = interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match self.purge().await {
+                Ok(0) => {}
+                Ok(purged) => info!("Purged {} expired links", purged),
+                Err(e) => error!("Error purging expired links: {:?}", e),
+            }
+        }
+    }
+
+    async fn purge(&self) -> Result<usize, AppError> {
+        let purged = self.storage.purge_expired().await?;
+        for id in &purged {
+            self.cache.invalidate(id);
+        }
+        Ok(purged.len())
+    }
+}
+
+#[instrument]
+async fn shorten(
+    State(state): State<AppState>,
+    url: Result<Json<ShortenRequest>, JsonRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let Json(url) = url?;
+    let target = normalize_url(&url.url, state.sort_query_params)?;
+    validate_limits(&url.limits)?;
+    let id = match &url.alias {
+        Some(alias) => {
+            validate_alias(alias)?;
+            state
+                .shorten_with_alias(&target, alias, &url.limits)
+                .await?
+        }
+        None => state.shorten(&target, &url.limits).await?,
+    };
+    let body = Json(ShortenResponse {
+        url: format!("{}/{}", state.base_url, id),
+    });
+    Ok((StatusCode::CREATED, body))
+}
+
+#[instrument(skip(headers))]
+async fn redirect(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let row = state.get_url(&id).await?;
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .map(str::to_string)
+    };
+    state.record_click(Click {
+        url_id: id,
+        clicked_at: Utc::now(),
+        referrer: header(header::REFERER),
+        user_agent: header(header::USER_AGENT),
+        client_ip: coarse_ip(addr.ip()),
+    });
+    // browsers keep permanent redirects and would skip the click count and the limits
+    Ok(Redirect::temporary(&row.url))
+}
+
+async fn metrics(State(state): State<AppState>) -> Json<MetricsResponse> {
+    Json(MetricsResponse {
+        cache: state.cache.stats(),
+    })
+}
+
+#[instrument]
+async fn stats(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<StatsResponse>, AppError> {
+    Ok(Json(state.stats(&id).await?))
+}
+
+/// write queued clicks in batches, a batch is written once it is full or its first click
+/// waited for `CLICK_FLUSH_INTERVAL`
+async fn record_clicks(storage: Arc<dyn Storage>, mut receiver: mpsc::Receiver<Click>) {
+    let mut batch = Vec::with_capacity(CLICK_BATCH_SIZE);
+    while receiver.recv_many(&mut batch, CLICK_BATCH_SIZE).await > 0 {
+        let deadline = Instant::now() + CLICK_FLUSH_INTERVAL;
+        while batch.len() < CLICK_BATCH_SIZE {
+            let limit = CLICK_BATCH_SIZE - batch.len();
+            match timeout_at(deadline, receiver.recv_many(&mut batch, limit)).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+        if let Err(e) = storage.insert_clicks(&batch).await {
+            error!("Error writing {} clicks: {:?}", batch.len(), e);
+        }
+        batch.clear();
+    }
+}
+
+/// the /24 of an IPv4 or the /48 of an IPv6 client, enough for rough geography
+/// without storing who clicked
+fn coarse_ip(ip: IpAddr) -> String {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}/24", Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            format!("{}/48", Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+/// try generated ids until `insert` stores one, it returns `None` when the id is taken
+async fn with_unique_id<F, Fut>(
+    ids: &IdGenerator,
+    max_attempts: usize,
+    mut insert: F,
+) -> Result<String, AppError>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<Option<String>, AppError>>,
+{
+    for attempt in 1..=max_attempts {
+        let id = ids.generate();
+        match insert(id.clone()).await? {
+            Some(id) => return Ok(id),
+            None => warn!(
+                "Generated id {} is taken, attempt {} of {}",
+                id, attempt, max_attempts
+            ),
+        }
+    }
+    Err(anyhow!("no free id after {} attempts", max_attempts).into())
+}
+
+/// parse and check `url`, then spell it the one way it is stored, so equal urls share a link,
+/// the host is lowercased and a default port dropped by the parser
+fn normalize_url(url: &str, sort_query: bool) -> Result<String, AppError> {
+    let invalid = |msg: String| Err(AppError::InvalidInput(msg));
+    let too_long = || {
+        invalid(format!(
+            "url must be at most {} characters long",
+            MAX_URL_LEN
+        ))
+    };
+    if url.len() > MAX_URL_LEN {
+        return too_long();
+    }
+    let mut url = match Url::parse(url.trim()) {
+        Ok(url) => url,
+        Err(e) => return invalid(format!("url is not valid: {}", e)),
+    };
+    if !ALLOWED_SCHEMES.contains(&url.scheme()) {
+        return invalid(format!(
+            "url scheme {} is not allowed, use one of: {}",
+            url.scheme(),
+            ALLOWED_SCHEMES.join(", ")
+        ));
+    }
+    if url.host_str().unwrap_or_default().is_empty() {
+        return invalid("url must have a host".to_string());
+    }
+    if sort_query && url.query().is_some() {
+        let mut params: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        // stable, so repeated params keep their order
+        params.sort_by(|a, b| a.0.cmp(&b.0));
+        url.query_pairs_mut().clear().extend_pairs(params);
+    }
+    if url.query() == Some("") {
+        url.set_query(None);
+    }
+    // normalizing may add a few characters, e.g. percent-encoding
+    let url = String::from(url);
+    if url.len() > MAX_URL_LEN {
+        return too_long();
+    }
+    Ok(url)
+}
+
+fn validate_alias(alias: &str) -> Result<(), AppError> {
+    if !(MIN_ALIAS_LEN..=MAX_ALIAS_LEN).contains(&alias.len()) {
+        return Err(AppError::InvalidInput(format!(
+            "alias must be {} to {} characters long",
+            MIN_ALIAS_LEN, MAX_ALIAS_LEN
+        )));
+    }
+    if !alias.chars().all(is_alias_char) {
+        return Err(AppError::InvalidInput(
+            "alias may only contain letters, digits, '-' and '_'".to_string(),
+        ));
+    }
+    if RESERVED_ALIASES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(alias))
+    {
+        return Err(AppError::InvalidInput(format!(
+            "alias {} is reserved",
+            alias
+        )));
+    }
+    Ok(())
+}
+
+fn validate_limits(limits: &Limits) -> Result<(), AppError> {
+    if limits
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= Utc::now())
+    {
+        let msg = "expires_at must be in the future".to_string();
+        return Err(AppError::InvalidInput(msg));
+    }
+    if limits.max_clicks.is_some_and(|max_clicks| max_clicks < 1) {
+        let msg = "max_clicks must be at least 1".to_string();
+        return Err(AppError::InvalidInput(msg));
+    }
+    Ok(())
+}
+
+fn is_alias_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+impl IdGenerator {
+    /// ids end up in urls and in the id column, so the alphabet and length are limited
+    /// like those of an alias
+    fn nanoid(len: usize, alphabet: &[char]) -> Result<Self> {
+        ensure!(
+            (1..=MAX_ALIAS_LEN).contains(&len),
+            "id length must be 1 to {}",
+            MAX_ALIAS_LEN
+        );
+        ensure!(
+            alphabet.len() >= 2,
+            "id alphabet needs at least 2 characters"
+        );
+        ensure!(
+            alphabet.iter().all(|&c| is_alias_char(c)),
+            "id alphabet may only contain letters, digits, '-' and '_'"
+        );
+        let alphabet = alphabet.to_vec();
+        Ok(Self {
+            generate: Arc::new(move || nanoid!(len, &alphabet)),
+        })
+    }
+
+    fn generate(&self) -> String {
+        (self.generate)()
+    }
+}
+
+impl fmt::Debug for IdGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdGenerator").finish_non_exhaustive()
+    }
+}
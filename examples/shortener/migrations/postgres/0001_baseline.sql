@@ -0,0 +1,32 @@
+-- the schema the service used to create on every start, written to pick up a database
+-- from any earlier version of it as well as an empty one
+CREATE TABLE IF NOT EXISTS urls (id VARCHAR(32) PRIMARY KEY, url TEXT NOT NULL);
+
+-- tables created before custom aliases only fit generated ids, the check spares the
+-- table rewrite and its exclusive lock when the column is wide enough already
+DO $$
+BEGIN
+    IF (SELECT character_maximum_length FROM information_schema.columns
+        WHERE table_schema = current_schema() AND table_name = 'urls' AND column_name = 'id') < 32 THEN
+        ALTER TABLE urls ALTER COLUMN id TYPE VARCHAR(32);
+    END IF;
+END
+$$;
+
+ALTER TABLE urls ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ;
+ALTER TABLE urls ADD COLUMN IF NOT EXISTS max_clicks BIGINT;
+ALTER TABLE urls ADD COLUMN IF NOT EXISTS clicks BIGINT NOT NULL DEFAULT 0;
+
+-- only links without limits are shared by everyone shortening the same url
+ALTER TABLE urls DROP CONSTRAINT IF EXISTS urls_url_key;
+CREATE UNIQUE INDEX IF NOT EXISTS urls_unlimited_url_key ON urls (url) WHERE expires_at IS NULL AND max_clicks IS NULL;
+
+CREATE TABLE IF NOT EXISTS clicks (
+    id BIGSERIAL PRIMARY KEY,
+    url_id VARCHAR(32) NOT NULL,
+    clicked_at TIMESTAMPTZ NOT NULL,
+    referrer TEXT,
+    user_agent TEXT,
+    client_ip TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS clicks_url_id_clicked_at ON clicks (url_id, clicked_at);
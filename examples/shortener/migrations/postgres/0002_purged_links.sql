@@ -0,0 +1,2 @@
+-- purged links stay behind without their url so their ids are never handed out again
+ALTER TABLE urls ADD COLUMN IF NOT EXISTS purged BOOLEAN NOT NULL DEFAULT FALSE;
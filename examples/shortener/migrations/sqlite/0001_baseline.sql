@@ -0,0 +1,20 @@
+-- the schema the service used to create on every start, a database from before migrations
+-- is picked up as it is
+CREATE TABLE IF NOT EXISTS urls (
+    id TEXT PRIMARY KEY,
+    url TEXT NOT NULL,
+    expires_at TEXT,
+    max_clicks INTEGER,
+    clicks INTEGER NOT NULL DEFAULT 0
+);
+CREATE UNIQUE INDEX IF NOT EXISTS urls_unlimited_url_key ON urls (url) WHERE expires_at IS NULL AND max_clicks IS NULL;
+
+CREATE TABLE IF NOT EXISTS clicks (
+    id INTEGER PRIMARY KEY,
+    url_id TEXT NOT NULL,
+    clicked_at TEXT NOT NULL,
+    referrer TEXT,
+    user_agent TEXT,
+    client_ip TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS clicks_url_id_clicked_at ON clicks (url_id, clicked_at);
@@ -0,0 +1,4 @@
+-- links under a custom alias are never shared, so they stay out of the url dedup
+ALTER TABLE urls ADD COLUMN alias BOOLEAN NOT NULL DEFAULT FALSE;
+DROP INDEX IF EXISTS urls_unlimited_url_key;
+CREATE UNIQUE INDEX urls_unlimited_url_key ON urls (url) WHERE expires_at IS NULL AND max_clicks IS NULL AND NOT alias;
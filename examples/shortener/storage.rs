@@ -0,0 +1,162 @@
+mod memory;
+mod postgres;
+mod sqlite;
+
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{
+    migrate::{Migrate, Migrator},
+    Database, FromRow, Pool,
+};
+use tracing::info;
+
+pub use memory::MemoryStorage;
+pub use postgres::PgStorage;
+pub use sqlite::SqliteStorage;
+
+use crate::{
+    config::{Config, Migrations},
+    error::AppError,
+    Click, DailyClicks, Limits, ReferrerClicks,
+};
+
+#[derive(Debug, Clone, FromRow)]
+pub struct UrlRow {
+    pub id: String,
+    pub url: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_clicks: Option<i64>,
+}
+
+/// where links and their clicks are kept, the rules about expiry and limits live in `AppState`
+#[async_trait]
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// store a link under `id`, a url without limits that is stored already keeps its link,
+    /// returns the id the url ended up under, `None` if `id` is taken
+    async fn insert(
+        &self,
+        id: &str,
+        url: &str,
+        limits: &Limits,
+    ) -> Result<Option<String>, AppError>;
+
+    /// store a link under a custom alias, it is never shared with other requests for the url,
+    /// `false` if the alias is taken
+    async fn insert_alias(&self, alias: &str, url: &str, limits: &Limits)
+        -> Result<bool, AppError>;
+
+    async fn get(&self, id: &str) -> Result<Option<UrlRow>, AppError>;
+
+    /// count a click on a link with a click limit, `false` once it is used up,
+    /// concurrent clicks can not exceed the limit
+    async fn claim_click(&self, id: &str) -> Result<bool, AppError>;
+
+    async fn insert_clicks(&self, clicks: &[Click]) -> Result<(), AppError>;
+
+    /// clicks by UTC day, oldest first
+    async fn daily_clicks(&self, id: &str) -> Result<Vec<DailyClicks>, AppError>;
+
+    /// most clicks first, ties by referrer
+    async fn top_referrers(&self, id: &str, limit: i64) -> Result<Vec<ReferrerClicks>, AppError>;
+
+    /// drop the url of expired and used up links, returns their ids, the links are kept with
+    /// their limits and clicks so their ids stay taken, keep answering `Gone` and keep their stats
+    async fn purge_expired(&self) -> Result<Vec<String>, AppError>;
+}
+
+/// pick the backend by the scheme of `db_url`
+pub async fn connect(config: &Config) -> Result<Arc<dyn Storage>> {
+    let scheme = config.db_url.split(':').next().unwrap_or_default();
+    let storage: Arc<dyn Storage> = match scheme {
+        "postgres" | "postgresql" => Arc::new(
+            PgStorage::connect(&config.db_url, config.db_pool_size, config.migrations).await?,
+        ),
+        "sqlite" => Arc::new(
+            SqliteStorage::connect(&config.db_url, config.db_pool_size, config.migrations).await?,
+        ),
+        "memory" => Arc::new(MemoryStorage::default()),
+        _ => bail!(
+            "Unsupported db_url, use postgres://, sqlite: or memory: {}",
+            config.redacted_db_url()
+        ),
+    };
+    Ok(storage)
+}
+
+/// apply the pending migrations, or in check mode refuse to go on while there are any,
+/// `migrated` tells if the database has the table that records them
+async fn migrate<DB>(
+    migrator: &Migrator,
+    db: &Pool<DB>,
+    mode: Migrations,
+    migrated: bool,
+) -> Result<()>
+where
+    DB: Database,
+    DB::Connection: Migrate,
+{
+    let mut conn = db.acquire().await?;
+    let pending = pending_migrations(migrator, &mut *conn, migrated).await?;
+    drop(conn);
+    match mode {
+        _ if pending.is_empty() => info!("Database schema is up to date"),
+        Migrations::Check => bail!(
+            "Database schema is behind, pending migrations: {:?}",
+            pending
+        ),
+        Migrations::Run => {
+            migrator.run(db).await?;
+            info!("Applied migrations: {:?}", pending);
+        }
+    }
+    Ok(())
+}
+
+/// versions not applied yet, a migration that failed halfway or changed since it was applied
+/// is an error like it is for `Migrator::run`
+async fn pending_migrations<C>(
+    migrator: &Migrator,
+    conn: &mut C,
+    migrated: bool,
+) -> Result<Vec<i64>>
+where
+    C: Migrate,
+{
+    let applied: HashMap<i64, Vec<u8>> = if migrated {
+        if let Some(version) = conn.dirty_version().await? {
+            bail!("Migration {} was applied partially", version);
+        }
+        conn.list_applied_migrations()
+            .await?
+            .into_iter()
+            .map(|migration| (migration.version, migration.checksum.into_owned()))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let mut pending = Vec::new();
+    for migration in migrator.iter() {
+        if migration.migration_type.is_down_migration() {
+            continue;
+        }
+        match applied.get(&migration.version) {
+            Some(checksum) if *checksum == *migration.checksum => {}
+            Some(_) => bail!(
+                "Migration {} changed after it was applied",
+                migration.version
+            ),
+            None => pending.push(migration.version),
+        }
+    }
+    Ok(pending)
+}
+
+impl Limits {
+    /// links without limits are shared by everyone shortening the same url
+    pub fn is_unlimited(&self) -> bool {
+        self.expires_at.is_none() && self.max_clicks.is_none()
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::{BTreeMap, HashMap};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use dashmap::{mapref::entry::Entry, DashMap};
+
+use super::{Storage, UrlRow};
+use crate::{error::AppError, Click, DailyClicks, Limits, ReferrerClicks};
+
+/// links that live as long as the process, for tests and trying things out
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    links: DashMap<String, Link>,
+    /// ids of the links without limits by url, what the unique index is to the databases
+    unlimited: DashMap<String, String>,
+    clicks: DashMap<String, Vec<Click>>,
+}
+
+#[derive(Debug)]
+struct Link {
+    row: UrlRow,
+    clicks: i64,
+    purged: bool,
+}
+
+impl MemoryStorage {
+    /// `false` if `id` is taken
+    fn insert_link(&self, id: &str, url: &str, limits: &Limits) -> bool {
+        match self.links.entry(id.to_string()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(Link {
+                    row: UrlRow {
+                        id: id.to_string(),
+                        url: url.to_string(),
+                        expires_at: limits.expires_at,
+                        max_clicks: limits.max_clicks,
+                    },
+                    clicks: 0,
+                    purged: false,
+                });
+                true
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn insert(
+        &self,
+        id: &str,
+        url: &str,
+        limits: &Limits,
+    ) -> Result<Option<String>, AppError> {
+        if !limits.is_unlimited() {
+            return Ok(self.insert_link(id, url, limits).then(|| id.to_string()));
+        }
+        // the url stays locked until the link is stored, so concurrent requests share it
+        let entry = match self.unlimited.entry(url.to_string()) {
+            Entry::Occupied(entry) => return Ok(Some(entry.get().clone())),
+            Entry::Vacant(entry) => entry,
+        };
+        if !self.insert_link(id, url, limits) {
+            return Ok(None);
+        }
+        entry.insert(id.to_string());
+        Ok(Some(id.to_string()))
+    }
+
+    async fn insert_alias(
+        &self,
+        alias: &str,
+        url: &str,
+        limits: &Limits,
+    ) -> Result<bool, AppError> {
+        Ok(self.insert_link(alias, url, limits))
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<UrlRow>, AppError> {
+        Ok(self.links.get(id).map(|link| link.row.clone()))
+    }
+
+    async fn claim_click(&self, id: &str) -> Result<bool, AppError> {
+        let Some(mut link) = self.links.get_mut(id) else {
+            return Ok(false);
+        };
+        match link.row.max_clicks {
+            Some(max_clicks) if link.clicks < max_clicks => {
+                link.clicks += 1;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn insert_clicks(&self, clicks: &[Click]) -> Result<(), AppError> {
+        for click in clicks {
+            self.clicks
+                .entry(click.url_id.clone())
+                .or_default()
+                .push(click.clone());
+        }
+        Ok(())
+    }
+
+    async fn daily_clicks(&self, id: &str) -> Result<Vec<DailyClicks>, AppError> {
+        let mut daily = BTreeMap::new();
+        if let Some(clicks) = self.clicks.get(id) {
+            for click in clicks.iter() {
+                *daily.entry(click.clicked_at.date_naive()).or_default() += 1;
+            }
+        }
+        let daily = daily
+            .into_iter()
+            .map(|(date, clicks)| DailyClicks { date, clicks })
+            .collect();
+        Ok(daily)
+    }
+
+    async fn top_referrers(&self, id: &str, limit: i64) -> Result<Vec<ReferrerClicks>, AppError> {
+        let mut referrers: HashMap<&str, i64> = HashMap::new();
+        let clicks = self.clicks.get(id);
+        for click in clicks.iter().flat_map(|clicks| clicks.iter()) {
+            if let Some(referrer) = &click.referrer {
+                *referrers.entry(referrer).or_default() += 1;
+            }
+        }
+        let mut top_referrers: Vec<ReferrerClicks> = referrers
+            .into_iter()
+            .map(|(referrer, clicks)| ReferrerClicks {
+                referrer: referrer.to_string(),
+                clicks,
+            })
+            .collect();
+        top_referrers.sort_by(|a, b| b.clicks.cmp(&a.clicks).then(a.referrer.cmp(&b.referrer)));
+        top_referrers.truncate(limit.try_into().unwrap_or(0));
+        Ok(top_referrers)
+    }
+
+    async fn purge_expired(&self) -> Result<Vec<String>, AppError> {
+        let now = Utc::now();
+        let mut purged = Vec::new();
+        // links without limits never expire, so `unlimited` is left alone
+        for mut link in self.links.iter_mut() {
+            let expired = link
+                .row
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= now)
+                || link
+                    .row
+                    .max_clicks
+                    .is_some_and(|max_clicks| link.clicks >= max_clicks);
+            if expired && !link.purged {
+                link.row.url.clear();
+                link.purged = true;
+                purged.push(link.key().clone());
+            }
+        }
+        Ok(purged)
+    }
+}
@@ -0,0 +1,148 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use sqlx::{migrate::Migrator, postgres::PgPoolOptions, PgPool};
+
+use super::{Storage, UrlRow};
+use crate::{config::Migrations, error::AppError, Click, DailyClicks, Limits, ReferrerClicks};
+
+/// the first one brings databases from before migrations up to date, whatever their version
+static MIGRATOR: Migrator = sqlx::migrate!("examples/shortener/migrations/postgres");
+
+#[derive(Debug, Clone)]
+pub struct PgStorage {
+    db: PgPool,
+}
+
+impl PgStorage {
+    pub async fn connect(url: &str, pool_size: u32, migrations: Migrations) -> Result<Self> {
+        let db = PgPoolOptions::new()
+            .max_connections(pool_size)
+            .connect(url)
+            .await?;
+        let migrated: Option<String> =
+            sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations')::text")
+                .fetch_one(&db)
+                .await?;
+        super::migrate(&MIGRATOR, &db, migrations, migrated.is_some()).await?;
+        Ok(Self { db })
+    }
+}
+
+#[async_trait]
+impl Storage for PgStorage {
+    async fn insert(
+        &self,
+        id: &str,
+        url: &str,
+        limits: &Limits,
+    ) -> Result<Option<String>, AppError> {
+        let res = sqlx::query_scalar("INSERT INTO urls (id, url, expires_at, max_clicks) VALUES ($1, $2, $3, $4) ON CONFLICT(url) WHERE expires_at IS NULL AND max_clicks IS NULL AND NOT alias DO UPDATE SET url=EXCLUDED.url RETURNING id")
+            .bind(id)
+            .bind(url)
+            .bind(limits.expires_at)
+            .bind(limits.max_clicks)
+            .fetch_one(&self.db)
+            .await;
+        match res {
+            Ok(id) => Ok(Some(id)),
+            // a taken url is handled by ON CONFLICT, only the id can collide
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn insert_alias(
+        &self,
+        alias: &str,
+        url: &str,
+        limits: &Limits,
+    ) -> Result<bool, AppError> {
+        let res = sqlx::query(
+            "INSERT INTO urls (id, url, expires_at, max_clicks, alias) VALUES ($1, $2, $3, $4, TRUE)",
+        )
+        .bind(alias)
+        .bind(url)
+        .bind(limits.expires_at)
+        .bind(limits.max_clicks)
+        .execute(&self.db)
+        .await;
+        match res {
+            Ok(_) => Ok(true),
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<UrlRow>, AppError> {
+        let row = sqlx::query_as("SELECT id, url, expires_at, max_clicks FROM urls WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(row)
+    }
+
+    async fn claim_click(&self, id: &str) -> Result<bool, AppError> {
+        let claimed = sqlx::query(
+            "UPDATE urls SET clicks = clicks + 1 WHERE id = $1 AND clicks < max_clicks",
+        )
+        .bind(id)
+        .execute(&self.db)
+        .await?;
+        Ok(claimed.rows_affected() > 0)
+    }
+
+    async fn insert_clicks(&self, clicks: &[Click]) -> Result<(), AppError> {
+        let mut url_ids = Vec::with_capacity(clicks.len());
+        let mut clicked_at = Vec::with_capacity(clicks.len());
+        let mut referrers = Vec::with_capacity(clicks.len());
+        let mut user_agents = Vec::with_capacity(clicks.len());
+        let mut client_ips = Vec::with_capacity(clicks.len());
+        for click in clicks {
+            url_ids.push(click.url_id.as_str());
+            clicked_at.push(click.clicked_at);
+            referrers.push(click.referrer.as_deref());
+            user_agents.push(click.user_agent.as_deref());
+            client_ips.push(click.client_ip.as_str());
+        }
+        sqlx::query("INSERT INTO clicks (url_id, clicked_at, referrer, user_agent, client_ip) SELECT * FROM UNNEST($1::VARCHAR[], $2::TIMESTAMPTZ[], $3::TEXT[], $4::TEXT[], $5::TEXT[])")
+            .bind(url_ids)
+            .bind(clicked_at)
+            .bind(referrers)
+            .bind(user_agents)
+            .bind(client_ips)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn daily_clicks(&self, id: &str) -> Result<Vec<DailyClicks>, AppError> {
+        let daily = sqlx::query_as(
+            "SELECT (clicked_at AT TIME ZONE 'UTC')::date AS date, count(*) AS clicks FROM clicks WHERE url_id = $1 GROUP BY date ORDER BY date",
+        )
+        .bind(id)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(daily)
+    }
+
+    async fn top_referrers(&self, id: &str, limit: i64) -> Result<Vec<ReferrerClicks>, AppError> {
+        let top_referrers = sqlx::query_as(
+            "SELECT referrer, count(*) AS clicks FROM clicks WHERE url_id = $1 AND referrer IS NOT NULL GROUP BY referrer ORDER BY clicks DESC, referrer LIMIT $2",
+        )
+        .bind(id)
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(top_referrers)
+    }
+
+    async fn purge_expired(&self) -> Result<Vec<String>, AppError> {
+        let ids = sqlx::query_scalar(
+            "UPDATE urls SET url = '', purged = TRUE \
+             WHERE NOT purged AND (expires_at <= now() OR clicks >= max_clicks) RETURNING id",
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(ids)
+    }
+}
@@ -0,0 +1,157 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::Utc;
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    QueryBuilder, SqlitePool,
+};
+
+use super::{Storage, UrlRow};
+use crate::{config::Migrations, error::AppError, Click, DailyClicks, Limits, ReferrerClicks};
+
+static MIGRATOR: Migrator = sqlx::migrate!("examples/shortener/migrations/sqlite");
+
+/// timestamps are stored as RFC 3339 text, compared through `julianday` as the offsets
+/// and fractions of a second vary
+const EXPIRED: &str = "julianday(expires_at) <= julianday(?) OR clicks >= max_clicks";
+
+#[derive(Debug, Clone)]
+pub struct SqliteStorage {
+    db: SqlitePool,
+}
+
+impl SqliteStorage {
+    /// `sqlite:links.db` creates the file if needed, `sqlite::memory:` lives with the pool
+    pub async fn connect(url: &str, pool_size: u32, migrations: Migrations) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        // an in-memory database is gone once its last connection closes
+        let db = SqlitePoolOptions::new()
+            .max_connections(pool_size)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(options)
+            .await?;
+        let migrated: Option<String> = sqlx::query_scalar(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'",
+        )
+        .fetch_optional(&db)
+        .await?;
+        super::migrate(&MIGRATOR, &db, migrations, migrated.is_some()).await?;
+        Ok(Self { db })
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn insert(
+        &self,
+        id: &str,
+        url: &str,
+        limits: &Limits,
+    ) -> Result<Option<String>, AppError> {
+        let res = sqlx::query_scalar("INSERT INTO urls (id, url, expires_at, max_clicks) VALUES (?, ?, ?, ?) ON CONFLICT(url) WHERE expires_at IS NULL AND max_clicks IS NULL AND NOT alias DO UPDATE SET url=excluded.url RETURNING id")
+            .bind(id)
+            .bind(url)
+            .bind(limits.expires_at)
+            .bind(limits.max_clicks)
+            .fetch_one(&self.db)
+            .await;
+        match res {
+            Ok(id) => Ok(Some(id)),
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn insert_alias(
+        &self,
+        alias: &str,
+        url: &str,
+        limits: &Limits,
+    ) -> Result<bool, AppError> {
+        let res = sqlx::query(
+            "INSERT INTO urls (id, url, expires_at, max_clicks, alias) VALUES (?, ?, ?, ?, TRUE)",
+        )
+        .bind(alias)
+        .bind(url)
+        .bind(limits.expires_at)
+        .bind(limits.max_clicks)
+        .execute(&self.db)
+        .await;
+        match res {
+            Ok(_) => Ok(true),
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<UrlRow>, AppError> {
+        let row = sqlx::query_as("SELECT id, url, expires_at, max_clicks FROM urls WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(row)
+    }
+
+    async fn claim_click(&self, id: &str) -> Result<bool, AppError> {
+        let claimed =
+            sqlx::query("UPDATE urls SET clicks = clicks + 1 WHERE id = ? AND clicks < max_clicks")
+                .bind(id)
+                .execute(&self.db)
+                .await?;
+        Ok(claimed.rows_affected() > 0)
+    }
+
+    async fn insert_clicks(&self, clicks: &[Click]) -> Result<(), AppError> {
+        if clicks.is_empty() {
+            return Ok(());
+        }
+        let mut query = QueryBuilder::new(
+            "INSERT INTO clicks (url_id, clicked_at, referrer, user_agent, client_ip) ",
+        );
+        query.push_values(clicks, |mut row, click| {
+            row.push_bind(click.url_id.as_str())
+                .push_bind(click.clicked_at)
+                .push_bind(click.referrer.as_deref())
+                .push_bind(click.user_agent.as_deref())
+                .push_bind(click.client_ip.as_str());
+        });
+        query.build().execute(&self.db).await?;
+        Ok(())
+    }
+
+    async fn daily_clicks(&self, id: &str) -> Result<Vec<DailyClicks>, AppError> {
+        let daily = sqlx::query_as(
+            "SELECT date(clicked_at) AS date, count(*) AS clicks FROM clicks WHERE url_id = ? GROUP BY 1 ORDER BY 1",
+        )
+        .bind(id)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(daily)
+    }
+
+    async fn top_referrers(&self, id: &str, limit: i64) -> Result<Vec<ReferrerClicks>, AppError> {
+        let top_referrers = sqlx::query_as(
+            "SELECT referrer, count(*) AS clicks FROM clicks WHERE url_id = ? AND referrer IS NOT NULL GROUP BY referrer ORDER BY clicks DESC, referrer LIMIT ?",
+        )
+        .bind(id)
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(top_referrers)
+    }
+
+    async fn purge_expired(&self) -> Result<Vec<String>, AppError> {
+        let ids = sqlx::query_scalar(&format!(
+            "UPDATE urls SET url = '', purged = TRUE WHERE NOT purged AND ({}) RETURNING id",
+            EXPIRED
+        ))
+        .bind(Utc::now())
+        .fetch_all(&self.db)
+        .await?;
+        Ok(ids)
+    }
+}
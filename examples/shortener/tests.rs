@@ -0,0 +1,521 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use axum::{
+    body::{to_bytes, Body},
+    extract::connect_info::MockConnectInfo,
+    Router,
+};
+use chrono::{TimeDelta, Utc};
+use http::{header, HeaderMap, Method, Request, StatusCode};
+use serde_json::{json, Value};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteConnection},
+    Connection,
+};
+use tokio::time::sleep;
+use tower::ServiceExt;
+
+use crate::{
+    app,
+    cache::UrlCache,
+    coarse_ip,
+    config::{Config, Migrations},
+    error::AppError,
+    normalize_url,
+    storage::{SqliteStorage, Storage, UrlRow},
+    validate_alias, validate_limits, with_unique_id, AppState, IdGenerator, Limits,
+    CLICK_FLUSH_INTERVAL, ID_ALPHABET, MAX_ALIAS_LEN, MAX_ID_ATTEMPTS, MAX_URL_LEN, MIN_ALIAS_LEN,
+};
+
+/// every backend that needs no database server
+const BACKENDS: [&str; 2] = ["memory:", "sqlite::memory:"];
+
+async fn test_app(db_url: &str) -> Result<(Router, AppState)> {
+    let config = Config {
+        base_url: Some("https://sho.rt".to_string()),
+        db_url: db_url.to_string(),
+        ..Default::default()
+    };
+    let state = AppState::try_new(&config).await?;
+    let client = SocketAddr::from(([203, 0, 113, 77], 40000));
+    let app = app(state.clone()).layer(MockConnectInfo(client));
+    Ok((app, state))
+}
+
+/// the status, the headers and the json body, `Null` if there is none
+async fn call(app: &Router, request: Request<Body>) -> Result<(StatusCode, HeaderMap, Value)> {
+    let res = app.clone().oneshot(request).await?;
+    let status = res.status();
+    let headers = res.headers().clone();
+    let body = to_bytes(res.into_body(), usize::MAX).await?;
+    let body = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&body)?
+    };
+    Ok((status, headers, body))
+}
+
+async fn shorten(app: &Router, body: Value) -> Result<(StatusCode, Value)> {
+    let request = Request::post("/")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))?;
+    let (status, _, body) = call(app, request).await?;
+    Ok((status, body))
+}
+
+async fn get(app: &Router, uri: &str) -> Result<(StatusCode, HeaderMap, Value)> {
+    let request = Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header(header::REFERER, "https://news.example/")
+        .body(Body::empty())?;
+    call(app, request).await
+}
+
+/// hands out `ids` in order, then repeats the last one
+fn sequence(ids: &[&str]) -> IdGenerator {
+    let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+    let next = Mutex::new(0);
+    IdGenerator {
+        generate: Arc::new(move || {
+            let mut next = next.lock().unwrap();
+            let id = ids[(*next).min(ids.len() - 1)].clone();
+            *next += 1;
+            id
+        }),
+    }
+}
+
+/// insert into a set, `None` when the id is taken like a primary key violation
+async fn insert(
+    taken: &Mutex<HashSet<String>>,
+    attempts: &Mutex<Vec<String>>,
+    id: String,
+) -> Result<Option<String>, AppError> {
+    attempts.lock().unwrap().push(id.clone());
+    Ok(taken.lock().unwrap().insert(id.clone()).then_some(id))
+}
+
+#[tokio::test]
+async fn taken_ids_are_retried() -> Result<()> {
+    let taken = Mutex::new(HashSet::from(["aaaaaa".to_string(), "bbbbbb".to_string()]));
+    let attempts = Mutex::new(Vec::new());
+    let ids = sequence(&["aaaaaa", "bbbbbb", "cccccc"]);
+    let id = with_unique_id(&ids, MAX_ID_ATTEMPTS, |id| insert(&taken, &attempts, id)).await?;
+    assert_eq!(id, "cccccc");
+    assert_eq!(*attempts.lock().unwrap(), ["aaaaaa", "bbbbbb", "cccccc"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn retries_are_bounded() {
+    let taken = Mutex::new(HashSet::from(["aaaaaa".to_string()]));
+    let attempts = Mutex::new(Vec::new());
+    let ids = sequence(&["aaaaaa"]);
+    let res = with_unique_id(&ids, 3, |id| insert(&taken, &attempts, id)).await;
+    assert!(res.is_err());
+    assert_eq!(attempts.lock().unwrap().len(), 3);
+}
+
+#[test]
+fn client_ips_are_cut_down_to_their_network() {
+    assert_eq!(coarse_ip("203.0.113.77".parse().unwrap()), "203.0.113.0/24");
+    assert_eq!(
+        coarse_ip("::ffff:203.0.113.77".parse().unwrap()),
+        "203.0.113.0/24"
+    );
+    assert_eq!(
+        coarse_ip("2001:db8:1234:5678::1".parse().unwrap()),
+        "2001:db8:1234::/48"
+    );
+}
+
+#[test]
+fn equal_urls_are_spelled_the_same() -> Result<()> {
+    assert_eq!(
+        normalize_url("HTTPS://Example.COM:443/a/./b?", false)?,
+        "https://example.com/a/b"
+    );
+    assert_eq!(
+        normalize_url(" http://example.com:80 ", false)?,
+        "http://example.com/"
+    );
+    assert_eq!(
+        normalize_url("http://example.com:8080/?b=2&a=1&b=1", false)?,
+        "http://example.com:8080/?b=2&a=1&b=1"
+    );
+    assert_eq!(
+        normalize_url("http://example.com:8080/?b=2&a=1&b=1", true)?,
+        "http://example.com:8080/?a=1&b=2&b=1"
+    );
+    Ok(())
+}
+
+#[test]
+fn invalid_urls_are_rejected() {
+    let long = format!("https://example.com/{}", "a".repeat(MAX_URL_LEN));
+    for url in [
+        "not a url",
+        "/relative/path",
+        "javascript:alert(1)",
+        "ftp://example.com/file",
+        "http://",
+        &long,
+    ] {
+        let res = normalize_url(url, false);
+        assert!(
+            matches!(res, Err(AppError::InvalidInput(_))),
+            "{}: {:?}",
+            url,
+            res
+        );
+    }
+}
+
+#[test]
+fn aliases_are_validated() {
+    let shortest = "a".repeat(MIN_ALIAS_LEN);
+    let longest = "a".repeat(MAX_ALIAS_LEN);
+    for alias in [shortest.as_str(), &longest, "my-link_2", "Admins"] {
+        assert!(validate_alias(alias).is_ok(), "{}", alias);
+    }
+    let too_short = "a".repeat(MIN_ALIAS_LEN - 1);
+    let too_long = "a".repeat(MAX_ALIAS_LEN + 1);
+    for alias in [
+        too_short.as_str(),
+        &too_long,
+        "with space",
+        "slash/link",
+        "dot.link",
+        "ünïcode",
+        "admin",
+        "API",
+        "Stats",
+    ] {
+        let res = validate_alias(alias);
+        assert!(
+            matches!(res, Err(AppError::InvalidInput(_))),
+            "{}: {:?}",
+            alias,
+            res
+        );
+    }
+}
+
+#[test]
+fn limits_are_validated() {
+    let valid = [
+        Limits::default(),
+        Limits {
+            expires_at: Some(Utc::now() + TimeDelta::hours(1)),
+            max_clicks: Some(1),
+        },
+    ];
+    for limits in valid {
+        assert!(validate_limits(&limits).is_ok(), "{:?}", limits);
+    }
+    let invalid = [
+        Limits {
+            expires_at: Some(Utc::now() - TimeDelta::seconds(1)),
+            max_clicks: None,
+        },
+        Limits {
+            expires_at: None,
+            max_clicks: Some(0),
+        },
+        Limits {
+            expires_at: None,
+            max_clicks: Some(-1),
+        },
+    ];
+    for limits in invalid {
+        let res = validate_limits(&limits);
+        assert!(
+            matches!(res, Err(AppError::InvalidInput(_))),
+            "{:?}: {:?}",
+            limits,
+            res
+        );
+    }
+}
+
+#[test]
+fn environment_overrides_the_config_file() -> Result<()> {
+    let config: Config = serde_yaml::from_str(
+        "base_url: https://sho.rt/s/\nlisten_addr: 0.0.0.0:8080\ndb_pool_size: 10\n",
+    )?;
+    let env = HashMap::from([
+        ("SHORTENER_DB_POOL_SIZE", "20"),
+        (
+            "SHORTENER_DB_URL",
+            "postgres://app:secret@db:5432/shortener",
+        ),
+    ]);
+    let config = config.with_env(|name| env.get(name).map(|value| value.to_string()))?;
+    config.validate()?;
+    assert_eq!(config.base_url(), "https://sho.rt/s");
+    assert_eq!(config.listen_addr, "0.0.0.0:8080".parse()?);
+    assert_eq!(config.db_pool_size, 20);
+    assert_eq!(
+        config.redacted_db_url(),
+        "postgres://app:***@db:5432/shortener"
+    );
+    assert_eq!(config.id_len, 6);
+
+    let env = HashMap::from([("SHORTENER_DB_POOL_SIZE", "many")]);
+    let res = Config::default().with_env(|name| env.get(name).map(|value| value.to_string()));
+    assert!(res.is_err());
+    Ok(())
+}
+
+#[test]
+fn invalid_config_is_rejected() {
+    let invalid = [
+        "db_pool_size: 0",
+        "base_url: sho.rt",
+        "base_url: ftp://sho.rt",
+        "base_url: https://sho.rt/?x=1",
+        "id_alphabet: a/b",
+        "id_len: 0",
+        "listen_addr: localhost",
+        "max_conn: 10",
+    ];
+    for content in invalid {
+        let res = serde_yaml::from_str::<Config>(content)
+            .map_err(anyhow::Error::from)
+            .and_then(|config| config.validate());
+        assert!(res.is_err(), "{}", content);
+    }
+    assert_eq!(Config::default().base_url(), "http://127.0.0.1:1234");
+}
+
+#[test]
+fn generated_ids_follow_length_and_alphabet() -> Result<()> {
+    let ids = IdGenerator::nanoid(10, &['x', 'y'])?;
+    for _ in 0..100 {
+        let id = ids.generate();
+        assert_eq!(id.len(), 10);
+        assert!(id.chars().all(|c| c == 'x' || c == 'y'), "{}", id);
+    }
+    assert!(IdGenerator::nanoid(0, &ID_ALPHABET).is_err());
+    assert!(IdGenerator::nanoid(MAX_ALIAS_LEN + 1, &ID_ALPHABET).is_err());
+    assert!(IdGenerator::nanoid(6, &['a']).is_err());
+    assert!(IdGenerator::nanoid(6, &['a', '/']).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn links_are_shortened_redirected_and_counted() -> Result<()> {
+    for db_url in BACKENDS {
+        let (app, _) = test_app(db_url).await?;
+        let (status, body) = shorten(&app, json!({"url": "HTTPS://Example.com:443/a"})).await?;
+        assert_eq!(status, StatusCode::CREATED, "{}", db_url);
+        let link = body["url"].as_str().unwrap_or_default().to_string();
+        let id = link.strip_prefix("https://sho.rt/").unwrap_or_default();
+        assert!(!id.is_empty(), "{}: {}", db_url, link);
+
+        let (_, body) = shorten(&app, json!({"url": "https://example.com/a"})).await?;
+        assert_eq!(body["url"], link.as_str(), "{}", db_url);
+
+        let (status, headers, _) = get(&app, &format!("/{}", id)).await?;
+        assert_eq!(status, StatusCode::TEMPORARY_REDIRECT, "{}", db_url);
+        assert_eq!(headers[header::LOCATION], "https://example.com/a");
+
+        sleep(CLICK_FLUSH_INTERVAL * 2).await;
+        let (status, _, stats) = get(&app, &format!("/{}/stats", id)).await?;
+        assert_eq!(status, StatusCode::OK, "{}", db_url);
+        assert_eq!(stats["total_clicks"], 1, "{}", db_url);
+        assert_eq!(stats["daily"].as_array().map(Vec::len), Some(1));
+        assert_eq!(
+            stats["top_referrers"],
+            json!([{"referrer": "https://news.example/", "clicks": 1}]),
+            "{}",
+            db_url
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn aliases_and_limits_are_enforced() -> Result<()> {
+    for db_url in BACKENDS {
+        let (app, state) = test_app(db_url).await?;
+        // a url shortened before under a random id can still get an alias of its own
+        let (status, body) = shorten(&app, json!({"url": "https://example.com/q3"})).await?;
+        assert_eq!(status, StatusCode::CREATED, "{}", db_url);
+        let generated = body["url"].clone();
+        let report = json!({"url": "https://example.com/q3", "alias": "q3-report"});
+        for _ in 0..2 {
+            let (status, body) = shorten(&app, report.clone()).await?;
+            assert_eq!(status, StatusCode::CREATED, "{}", db_url);
+            assert_eq!(body["url"], "https://sho.rt/q3-report");
+        }
+
+        let taken = json!({"url": "https://example.com/q4", "alias": "q3-report"});
+        let (status, body) = shorten(&app, taken).await?;
+        assert_eq!(status, StatusCode::CONFLICT, "{}", db_url);
+        assert_eq!(body["error"], "alias q3-report is already taken");
+
+        let shared = json!({"url": "https://example.com/q3", "alias": "report"});
+        let (status, body) = shorten(&app, shared).await?;
+        assert_eq!(status, StatusCode::CREATED, "{}", db_url);
+        assert_eq!(body["url"], "https://sho.rt/report");
+        // the aliases are not shared with requests without one
+        let (_, body) = shorten(&app, json!({"url": "https://example.com/q3"})).await?;
+        assert_eq!(body["url"], generated, "{}", db_url);
+
+        let once = json!({"url": "https://example.com/q3", "alias": "once", "max_clicks": 1});
+        let (status, _) = shorten(&app, once.clone()).await?;
+        assert_eq!(status, StatusCode::CREATED, "{}", db_url);
+        let (status, _, _) = get(&app, "/once").await?;
+        assert_eq!(status, StatusCode::TEMPORARY_REDIRECT, "{}", db_url);
+        let (status, _, body) = get(&app, "/once").await?;
+        assert_eq!(status, StatusCode::GONE, "{}", db_url);
+        assert_eq!(body["error"], "link once expired");
+
+        assert_eq!(state.purge().await?, 1, "{}", db_url);
+        assert_eq!(state.purge().await?, 0, "{}", db_url);
+        // the purged link keeps its id, it is never handed out again
+        let (status, _, body) = get(&app, "/once").await?;
+        assert_eq!(status, StatusCode::GONE, "{}", db_url);
+        assert_eq!(body["error"], "link once expired");
+        let (status, body) = shorten(&app, once).await?;
+        assert_eq!(status, StatusCode::CONFLICT, "{}", db_url);
+        assert_eq!(body["error"], "alias once is already taken");
+        // the analytics of a finished link outlive the purge
+        sleep(CLICK_FLUSH_INTERVAL * 2).await;
+        let (status, _, stats) = get(&app, "/once/stats").await?;
+        assert_eq!(status, StatusCode::OK, "{}", db_url);
+        assert_eq!(stats["total_clicks"], 1, "{}", db_url);
+
+        let (status, body) = shorten(&app, json!({"url": "javascript:alert(1)"})).await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", db_url);
+        assert!(body["error"].is_string());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn check_mode_refuses_a_database_behind_the_migrations() -> Result<()> {
+    let path = env::temp_dir().join(format!("shortener-{}.db", std::process::id()));
+    let db_url = format!("sqlite:{}", path.display());
+    let res = SqliteStorage::connect(&db_url, 1, Migrations::Check).await;
+    assert!(res.is_err());
+    drop(SqliteStorage::connect(&db_url, 1, Migrations::Run).await?);
+    drop(SqliteStorage::connect(&db_url, 1, Migrations::Check).await?);
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn database_from_before_migrations_is_upgraded_in_place() -> Result<()> {
+    let path = env::temp_dir().join(format!("shortener-legacy-{}.db", std::process::id()));
+    let db_url = format!("sqlite:{}", path.display());
+    // the schema the service created on every start before it had migrations
+    let options = SqliteConnectOptions::from_str(&db_url)?.create_if_missing(true);
+    let mut conn = SqliteConnection::connect_with(&options).await?;
+    sqlx::raw_sql(
+        "CREATE TABLE IF NOT EXISTS urls (id TEXT PRIMARY KEY, url TEXT NOT NULL, \
+         expires_at TEXT, max_clicks INTEGER, clicks INTEGER NOT NULL DEFAULT 0);
+         CREATE UNIQUE INDEX IF NOT EXISTS urls_unlimited_url_key ON urls (url) \
+         WHERE expires_at IS NULL AND max_clicks IS NULL;
+         CREATE TABLE IF NOT EXISTS clicks (id INTEGER PRIMARY KEY, url_id TEXT NOT NULL, \
+         clicked_at TEXT NOT NULL, referrer TEXT, user_agent TEXT, client_ip TEXT NOT NULL);
+         CREATE INDEX IF NOT EXISTS clicks_url_id_clicked_at ON clicks (url_id, clicked_at);
+         INSERT INTO urls (id, url) VALUES ('legacy', 'https://example.com/legacy');",
+    )
+    .execute(&mut conn)
+    .await?;
+    conn.close().await?;
+
+    let storage = SqliteStorage::connect(&db_url, 1, Migrations::Run).await?;
+    let row = storage.get("legacy").await?;
+    assert_eq!(
+        row.map(|row| row.url).as_deref(),
+        Some("https://example.com/legacy")
+    );
+    drop(storage);
+    drop(SqliteStorage::connect(&db_url, 1, Migrations::Check).await?);
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+fn url_row(id: &str) -> UrlRow {
+    UrlRow {
+        id: id.to_string(),
+        url: format!("https://example.com/{}", id),
+        expires_at: None,
+        max_clicks: None,
+    }
+}
+
+#[tokio::test]
+async fn cache_keeps_links_and_unknown_ids_until_invalidated() -> Result<()> {
+    let cache = UrlCache::new(2, Duration::from_secs(60), Duration::from_secs(60));
+    let loads = AtomicUsize::new(0);
+    let load = |row: Option<UrlRow>| {
+        let loads = &loads;
+        async move {
+            loads.fetch_add(1, Ordering::Relaxed);
+            Ok(row)
+        }
+    };
+    for _ in 0..2 {
+        let row = cache.get_or_load("a", load(Some(url_row("a")))).await?;
+        assert_eq!(row.map(|row| row.id).as_deref(), Some("a"));
+        assert!(cache.get_or_load("x", load(None)).await?.is_none());
+    }
+    assert_eq!(loads.load(Ordering::Relaxed), 2);
+
+    cache.invalidate("x");
+    let row = cache.get_or_load("x", load(Some(url_row("x")))).await?;
+    assert!(row.is_some());
+    assert_eq!(loads.load(Ordering::Relaxed), 3);
+
+    // "a" is the least recently used, it makes room for "b"
+    cache.get_or_load("b", load(Some(url_row("b")))).await?;
+    cache.get_or_load("x", load(None)).await?;
+    cache.get_or_load("a", load(Some(url_row("a")))).await?;
+    assert_eq!(loads.load(Ordering::Relaxed), 5);
+
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses), (3, 5));
+    assert_eq!((stats.entries, stats.capacity), (2, 2));
+    Ok(())
+}
+
+#[tokio::test]
+async fn cache_entries_expire_and_racing_lookups_are_not_cached() -> Result<()> {
+    let cache = UrlCache::new(10, Duration::from_secs(60), Duration::from_millis(50));
+    cache.get_or_load("x", async { Ok(None) }).await?;
+    sleep(Duration::from_millis(100)).await;
+    let row = cache
+        .get_or_load("x", async { Ok(Some(url_row("x"))) })
+        .await?;
+    assert!(row.is_some());
+
+    // the link changes while it is loaded, what was loaded may be stale already
+    let load = async {
+        cache.invalidate("y");
+        Ok(None)
+    };
+    cache.get_or_load("y", load).await?;
+    let row = cache
+        .get_or_load("y", async { Ok(Some(url_row("y"))) })
+        .await?;
+    assert!(row.is_some());
+    assert_eq!(cache.stats().misses, 4);
+    Ok(())
+}